@@ -0,0 +1,137 @@
+use super::{Backend, BackendStream, CallbackInfo, ErrorCallback, RenderCallback};
+use crate::{
+    Error, Sample, StreamConfig,
+    convert::{Converter, FromSample},
+    device::{self, Device, Host, SampleFormat, SupportedConfig},
+};
+use cpal::traits::{DeviceTrait, StreamTrait};
+
+/// Plays through one of the system's audio hosts, using cpal. This is what OutputStream uses unless it's given
+/// another backend, and it's only available with the "cpal" feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpalBackend {
+    host: Host,
+}
+
+// Only holds the cpal stream, which stops when it's dropped
+struct CpalStream {
+    _stream: cpal::Stream,
+}
+
+impl CpalBackend {
+    /// Creates a backend which plays through the given host.
+    pub fn new(host: Host) -> Self {
+        Self { host }
+    }
+
+    /// Returns the host this backend plays through.
+    pub fn host(&self) -> Host {
+        self.host
+    }
+
+    fn find_device(&self, name: &str) -> Result<Device, Error> {
+        self.host.output_devices()?.into_iter().find(|device| device.name() == name).ok_or(Error::DeviceNotAvailable)
+    }
+}
+
+impl Default for CpalBackend {
+    /// Creates a backend which plays through the system's default host.
+    fn default() -> Self {
+        Self::new(device::default_host())
+    }
+}
+
+impl Backend for CpalBackend {
+    fn output_devices(&self) -> Result<Vec<String>, Error> {
+        Ok(self.host.output_devices()?.into_iter().map(|device| device.name).collect())
+    }
+
+    fn default_output_device(&self) -> Option<String> {
+        self.host.default_output_device().map(|device| device.name)
+    }
+
+    fn supported_configs(&self, device: &str) -> Result<Vec<SupportedConfig>, Error> {
+        self.find_device(device)?.supported_configs()
+    }
+
+    fn open(
+        &self,
+        device: &str,
+        config: StreamConfig,
+        render: RenderCallback,
+        mut error: ErrorCallback,
+    ) -> Result<Box<dyn BackendStream>, Error> {
+        let device = self.find_device(device)?;
+        let err_fn = move |err: cpal::StreamError| error(err.into());
+        let cpal_config = cpal::StreamConfig {
+            channels: config.channels,
+            sample_rate: cpal::SampleRate(config.sample_rate),
+            buffer_size: config.buffer_size.map_or(cpal::BufferSize::Default, cpal::BufferSize::Fixed),
+        };
+
+        let stream = match config.sample_format {
+            SampleFormat::F32 => {
+                let mut render = Timed::new(render);
+                device.inner.build_output_stream(
+                    &cpal_config,
+                    move |data: &mut [f32], info: &cpal::OutputCallbackInfo| render.call(data, info),
+                    err_fn,
+                )
+            },
+            SampleFormat::I16 => build_converted::<i16>(&device, &cpal_config, config, render, err_fn),
+            SampleFormat::U16 => build_converted::<u16>(&device, &cpal_config, config, render, err_fn),
+        }?;
+        stream.play()?;
+        Ok(Box::new(CpalStream { _stream: stream }))
+    }
+}
+
+impl BackendStream for CpalStream {}
+
+// A render callback which turns cpal's timestamps into times on the stream's own clock, starting from the first call
+struct Timed {
+    render: RenderCallback,
+    start: Option<cpal::StreamInstant>,
+}
+
+impl Timed {
+    fn new(render: RenderCallback) -> Self {
+        Self { render, start: None }
+    }
+
+    fn call(&mut self, data: &mut [Sample], info: &cpal::OutputCallbackInfo) {
+        let timestamp = info.timestamp();
+        let start = *self.start.get_or_insert(timestamp.callback);
+        let info = CallbackInfo {
+            callback: timestamp.callback.duration_since(&start),
+            playback: timestamp.playback.duration_since(&start),
+        };
+        (self.render)(data, info);
+    }
+}
+
+// Opens a stream in a format other than Sample, rendering into a buffer and converting from that
+fn build_converted<T>(
+    device: &Device,
+    cpal_config: &cpal::StreamConfig,
+    config: StreamConfig,
+    render: RenderCallback,
+    err_fn: impl FnMut(cpal::StreamError) + Send + 'static,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: FromSample + cpal::Sample,
+{
+    let mut render = Timed::new(render);
+    let mut converter = Converter::new(config.channels.into(), config.dither);
+    let mut buf: Vec<Sample> = Vec::with_capacity(config.expected_buffer_samples());
+    device.inner.build_output_stream(
+        cpal_config,
+        move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+            buf.clear();
+            buf.resize(data.len(), 0.0);
+            render.call(&mut buf, info);
+            converter.convert(&buf, data);
+        },
+        err_fn,
+    )
+}
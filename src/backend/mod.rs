@@ -0,0 +1,67 @@
+//! The layer between an OutputStream and the audio devices it plays on.
+//!
+//! A Backend lists devices, says which configurations they support, and opens streams on them which call back for
+//! samples. OutputStream only ever talks to devices through one, so anything which can ask for samples can play a
+//! Source: the system's audio API through `CpalBackend` (with the "cpal" feature, which is on by default), nothing at
+//! all through `NullBackend`, or an audio callback owned by someone else through `PullBackend`.
+//!
+//! Backends are shared between the thread which builds an OutputStream and the stream's background thread, so they
+//! have to be Send and Sync. Streams they open are only ever used from the background thread.
+
+#[cfg(feature = "cpal")]
+mod cpal;
+mod null;
+mod pull;
+
+#[cfg(feature = "cpal")]
+pub use self::cpal::CpalBackend;
+pub use null::NullBackend;
+pub use pull::PullBackend;
+
+use crate::{Error, Sample, StreamConfig, device::SupportedConfig};
+use std::{fmt, time::Duration};
+
+/// Called by a backend stream whenever it needs more samples. The buffer is interleaved in the stream's channel
+/// count, and must be filled completely.
+pub type RenderCallback = Box<dyn FnMut(&mut [Sample], CallbackInfo) + Send>;
+
+/// Called by a backend stream when something goes wrong. `Error::DeviceNotAvailable` means the device has been lost,
+/// and the OutputStream will look for another one.
+pub type ErrorCallback = Box<dyn FnMut(Error) + Send>;
+
+/// A source of audio devices, which OutputStream plays on. See the module documentation.
+pub trait Backend: Send + Sync + fmt::Debug {
+    /// Returns the names of every output device which is currently available.
+    fn output_devices(&self) -> Result<Vec<String>, Error>;
+
+    /// Returns the name of the default output device, or None if there isn't one.
+    fn default_output_device(&self) -> Option<String>;
+
+    /// Returns every configuration the named device supports.
+    fn supported_configs(&self, device: &str) -> Result<Vec<SupportedConfig>, Error>;
+
+    /// Starts playing on the named device with the given config, one of those from `supported_configs()`. Samples are
+    /// asked for through `render`, until the returned stream is dropped.
+    fn open(
+        &self,
+        device: &str,
+        config: StreamConfig,
+        render: RenderCallback,
+        error: ErrorCallback,
+    ) -> Result<Box<dyn BackendStream>, Error>;
+}
+
+/// A stream opened by a Backend. Dropping it stops playback, closes the device, and drops the render callback, which
+/// hands the Source back to the OutputStream.
+pub trait BackendStream {}
+
+/// Timing information passed along with each buffer a backend asks for. Times are measured on the stream's own clock,
+/// which starts from an arbitrary point, so they're only useful compared to each other.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallbackInfo {
+    /// When the buffer was asked for, if the backend knows.
+    pub callback: Option<Duration>,
+
+    /// When the first frame of the buffer is due to be played, if the backend knows.
+    pub playback: Option<Duration>,
+}
@@ -0,0 +1,114 @@
+use super::{Backend, BackendStream, CallbackInfo, ErrorCallback, RenderCallback};
+use crate::{
+    Error, StreamConfig,
+    device::{SampleFormat, SupportedConfig},
+};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// The name of the null backend's only device
+const DEVICE_NAME: &str = "Null";
+
+// What the null device supports. It doesn't really care, but the limits keep the buffers a sensible size.
+const MAX_CHANNELS: u16 = 32;
+const MAX_SAMPLE_RATE: u32 = 384000;
+const MAX_BUFFER_SIZE: u32 = 65536;
+const DEFAULT_BUFFER_SIZE: u32 = 512;
+
+/// A backend with one device, which asks for samples at the same pace as a real device would, then throws them away.
+/// This is useful for testing, and on systems with no audio devices at all.
+///
+/// The device, called "Null", supports any sample rate and up to 32 channels, in f32.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NullBackend;
+
+struct NullStream {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NullBackend {
+    /// Creates a null backend.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Backend for NullBackend {
+    fn output_devices(&self) -> Result<Vec<String>, Error> {
+        Ok(vec![DEVICE_NAME.into()])
+    }
+
+    fn default_output_device(&self) -> Option<String> {
+        Some(DEVICE_NAME.into())
+    }
+
+    fn supported_configs(&self, device: &str) -> Result<Vec<SupportedConfig>, Error> {
+        if device != DEVICE_NAME {
+            return Err(Error::DeviceNotAvailable)
+        }
+        Ok((1..=MAX_CHANNELS)
+            .map(|channels| SupportedConfig {
+                channels,
+                sample_rates: 1..=MAX_SAMPLE_RATE,
+                buffer_sizes: Some(1..=MAX_BUFFER_SIZE),
+                sample_format: SampleFormat::F32,
+            })
+            .collect())
+    }
+
+    fn open(
+        &self,
+        device: &str,
+        config: StreamConfig,
+        mut render: RenderCallback,
+        _error: ErrorCallback,
+    ) -> Result<Box<dyn BackendStream>, Error> {
+        if device != DEVICE_NAME {
+            return Err(Error::DeviceNotAvailable)
+        }
+        if config.channels == 0 || config.sample_rate == 0 {
+            return Err(Error::DeviceNotUsable)
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let running = running.clone();
+            let frames = config.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE).max(1);
+            let mut buffer = vec![0.0; frames as usize * usize::from(config.channels)];
+            thread::spawn(move || {
+                let start = Instant::now();
+                let mut played: u64 = 0;
+                while running.load(Ordering::Relaxed) {
+                    // Each buffer is due as soon as the one before it would have finished playing
+                    let due = Duration::from_secs_f64(played as f64 / f64::from(config.sample_rate));
+                    let now = start.elapsed();
+                    if due > now {
+                        thread::sleep(due - now);
+                    }
+                    let info = CallbackInfo { callback: Some(start.elapsed()), playback: Some(due) };
+                    render(&mut buffer, info);
+                    played += u64::from(frames);
+                }
+            })
+        };
+        Ok(Box::new(NullStream { running, thread: Some(thread) }))
+    }
+}
+
+impl BackendStream for NullStream {}
+
+impl Drop for NullStream {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use super::{Backend, BackendStream, CallbackInfo, ErrorCallback, RenderCallback};
+use crate::{
+    Error, Sample, StreamConfig,
+    device::{SampleFormat, SupportedConfig},
+};
+use std::{
+    fmt, ptr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicPtr, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+// The name of the pull backend's only device
+const DEVICE_NAME: &str = "Pull";
+
+// How often a closing stream checks whether `render()` has finished with its callback
+const CLOSE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A backend for when something else owns the audio device, such as a game engine with its own audio callback.
+/// Instead of asking a device for samples, the OutputStream waits for the owner of the PullBackend to ask for them
+/// with `render()`.
+///
+/// The backend has one device, called "Pull", which supports only the channel count and sample rate the backend was
+/// created with, in f32, and only one stream can play on it at a time. PullBackends are cheap to clone, and every
+/// clone renders from the same stream, so one can be given to `OutputStreamBuilder::backend()` and another kept to
+/// call `render()` on.
+#[derive(Clone)]
+pub struct PullBackend {
+    shared: Arc<PullShared>,
+}
+
+// The render callback is passed around without locks, like the Source in an OutputStream: whoever's rendering takes
+// it out of the slot for the length of the buffer, and puts it back afterwards. A stream is open for as long as
+// `open` is set, even while the slot is empty.
+struct PullShared {
+    channels: u16,
+    sample_rate: u32,
+    open: AtomicBool,
+    render: AtomicPtr<RenderCallback>,
+}
+
+struct PullStream {
+    shared: Arc<PullShared>,
+}
+
+impl PullBackend {
+    /// Creates a pull backend which will be asked for samples in the given channel count and sample rate.
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        Self {
+            shared: Arc::new(PullShared {
+                channels,
+                sample_rate,
+                open: AtomicBool::new(false),
+                render: AtomicPtr::new(ptr::null_mut()),
+            }),
+        }
+    }
+
+    /// Returns the channel count samples are rendered in.
+    pub fn channels(&self) -> u16 {
+        self.shared.channels
+    }
+
+    /// Returns the sample rate samples are rendered at.
+    pub fn sample_rate(&self) -> u32 {
+        self.shared.sample_rate
+    }
+
+    /// Returns true if an OutputStream is currently playing on this backend.
+    pub fn is_open(&self) -> bool {
+        self.shared.open.load(Ordering::Acquire)
+    }
+
+    /// Fills the buffer with interleaved samples from the OutputStream playing on this backend. The buffer's length
+    /// should be a multiple of the channel count.
+    ///
+    /// This never blocks. If there's no stream, it's in the middle of being opened or closed, or another thread is
+    /// rendering at the same time, the buffer is filled with silence instead.
+    pub fn render(&self, buffer: &mut [Sample]) {
+        self.render_with_info(buffer, CallbackInfo::default());
+    }
+
+    /// Like `render()`, but passes along timing information for the buffer, if the caller has any. The OutputStream
+    /// uses this to spot underruns.
+    pub fn render_with_info(&self, buffer: &mut [Sample], info: CallbackInfo) {
+        let render = self.shared.render.swap(ptr::null_mut(), Ordering::Acquire);
+        if render.is_null() {
+            buffer.iter_mut().for_each(|s| *s = 0.0);
+            return
+        }
+
+        // SAFETY: the slot only ever holds null or a pointer from Box::into_raw, and swapping it out means this thread
+        // has it to itself until it's put back. A stream being closed waits for that before dropping it.
+        unsafe { (*render)(buffer, info) };
+        self.shared.render.store(render, Ordering::Release);
+    }
+}
+
+impl fmt::Debug for PullBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PullBackend")
+            .field("channels", &self.shared.channels)
+            .field("sample_rate", &self.shared.sample_rate)
+            .finish()
+    }
+}
+
+impl Backend for PullBackend {
+    fn output_devices(&self) -> Result<Vec<String>, Error> {
+        Ok(vec![DEVICE_NAME.into()])
+    }
+
+    fn default_output_device(&self) -> Option<String> {
+        Some(DEVICE_NAME.into())
+    }
+
+    fn supported_configs(&self, device: &str) -> Result<Vec<SupportedConfig>, Error> {
+        if device != DEVICE_NAME {
+            return Err(Error::DeviceNotAvailable)
+        }
+        Ok(vec![SupportedConfig {
+            channels: self.shared.channels,
+            sample_rates: self.shared.sample_rate..=self.shared.sample_rate,
+            buffer_sizes: None,
+            sample_format: SampleFormat::F32,
+        }])
+    }
+
+    fn open(
+        &self,
+        device: &str,
+        config: StreamConfig,
+        render: RenderCallback,
+        _error: ErrorCallback,
+    ) -> Result<Box<dyn BackendStream>, Error> {
+        if device != DEVICE_NAME {
+            return Err(Error::DeviceNotAvailable)
+        }
+        if config.channels != self.shared.channels
+            || config.sample_rate != self.shared.sample_rate
+            || config.sample_format != SampleFormat::F32
+        {
+            return Err(Error::DeviceNotUsable)
+        }
+        if self.shared.open.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return Err(Error::DeviceNotAvailable)
+        }
+        self.shared.render.store(Box::into_raw(Box::new(render)), Ordering::Release);
+        Ok(Box::new(PullStream { shared: self.shared.clone() }))
+    }
+}
+
+impl BackendStream for PullStream {}
+
+impl Drop for PullStream {
+    // Waits for any `render()` in progress to finish with the callback, then drops it, which hands the Source back
+    fn drop(&mut self) {
+        loop {
+            let render = self.shared.render.swap(ptr::null_mut(), Ordering::Acquire);
+            if !render.is_null() {
+                // SAFETY: as in `render_with_info()`, and the callback is never put back once it's been taken here
+                drop(unsafe { Box::from_raw(render) });
+                break
+            }
+            thread::sleep(CLOSE_POLL_INTERVAL);
+        }
+        self.shared.open.store(false, Ordering::Release);
+    }
+}
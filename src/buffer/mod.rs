@@ -0,0 +1,833 @@
+use crate::{Sample, Source};
+use std::{
+    cell::UnsafeCell,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    slice,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+mod pool;
+
+use pool::Job;
+pub use pool::{ThreadPriority, WorkerPool};
+
+const DEF_BUFFER_SIZE: usize = 4800;
+
+// The longest a worker sleeps between checks, in case a wake-up is missed
+const WORKER_TIMEOUT: Duration = Duration::from_millis(10);
+
+// How many underruns an adaptive Buffer puts up with before it grows
+const UNDERRUNS_BEFORE_GROWTH: usize = 3;
+
+/// Adds threaded buffering to any Source object using an internal ring buffer.
+///
+/// A worker thread keeps the ring buffer topped up from the Source, so a Source which is slow or uneven, such as a
+/// decoder, can't hold up the audio thread. Reading from a Buffer never blocks or locks: if the worker hasn't kept up,
+/// the Buffer plays what it has followed by silence for the rest, and carries on from where it was once the worker
+/// catches up. The ring buffer is filled once before `new()` or `with_capacity()` returns, so playback doesn't start
+/// with a gap.
+///
+/// It should be noted that a Buffer will induce filter delay proportional to the buffer size.
+/// For example, the default size of 4800 samples will cause a 50 millisecond filter delay, assuming it's connected to
+/// a 48000 Hz and 2-channel output. Doubling the buffer size will double the resulting filter delay.
+///
+/// For consistent filter delay across systems, you may want to use a buffer size calculated from output variables,
+/// for example: `Buffer::with_capacity(sample_rate * channel_count / 20)`
+///
+/// To find out whether a Buffer is keeping up, get a BufferStats from `stats()` before handing it over to be played.
+/// If there's no telling how big a Buffer needs to be, `with_adaptive_capacity()` makes one which grows after
+/// repeated underruns. Each Buffer has its own worker thread unless it's given a WorkerPool to share, through
+/// `with_options()`.
+///
+/// Dropping a Buffer stops its worker as soon as it's finished with the Source, without waiting for it, so it's safe
+/// to do on the audio thread. The Source is then dropped by the worker, on the worker's thread. To get the Source
+/// back instead, or to be sure it's gone before carrying on, use `close()` or `close_timeout()`, or set a
+/// `BufferOptions::drop_timeout()`.
+pub struct Buffer<S>
+where
+    S: Source + Send + 'static,
+{
+    channel_count: usize,
+    shared: Arc<BufferShared>,
+    worker: Worker,
+
+    // Where the worker sends the Source when it stops, if it's asked to
+    returned: Receiver<S>,
+    drop_timeout: Option<Duration>,
+    closed: bool,
+
+    // The most the capacity may grow to, and how many underruns there have been since it last did
+    max_capacity: usize,
+    underruns: usize,
+}
+
+/// Options for creating a Buffer with `Buffer::with_options()`.
+#[derive(Clone)]
+pub struct BufferOptions {
+    capacity: usize,
+    max_capacity: Option<usize>,
+    pool: Option<WorkerPool>,
+    priority: ThreadPriority,
+    drop_timeout: Option<Duration>,
+}
+
+/// A handle for watching how well a Buffer is keeping up, which can be read from any thread while the Buffer plays.
+/// Get one with `Buffer::stats()`.
+///
+/// Sample counts include every channel, like the Buffer's capacity. The fill level is measured each time samples are
+/// read from the Buffer, just before they're read, so the low water mark is how close it's come to running out.
+/// Like StreamClocks, BufferStats are cheap to clone and never block.
+#[derive(Clone)]
+pub struct BufferStats(Arc<BufferShared>);
+
+struct BufferShared {
+    ring: Ring,
+
+    // How much of the ring the worker may fill. This is only less than the ring's capacity in adaptive Buffers.
+    capacity: AtomicUsize,
+
+    underruns: AtomicU64,
+    silence: AtomicU64,
+    low_water: AtomicUsize,
+    high_water: AtomicUsize,
+
+    // How long the worker last spent getting samples from the Source, and the longest it's ever spent, in nanoseconds
+    fill_time: AtomicU64,
+    max_fill_time: AtomicU64,
+
+    // Set by the worker once the Source has ended and everything it wrote is in the ring
+    finished: AtomicBool,
+
+    // Set when the Buffer is dropped or closed, to stop the worker, and whether the worker should send the Source
+    // back when it stops, rather than dropping it
+    closed: AtomicBool,
+    return_source: AtomicBool,
+}
+
+// A wait-free ring buffer with one producer (the worker) and one consumer (whoever's reading from the Buffer).
+// `head` and `tail` count every sample ever read and written, and are only wrapped to the capacity when indexing, so
+// the ring is empty when they're equal and full when they're a capacity apart. Each side only ever writes its own
+// counter, and only touches the part of the data the other side's counter says it's allowed to.
+struct Ring {
+    data: Box<[UnsafeCell<Sample>]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// Whatever's keeping a Buffer topped up
+enum Worker {
+    Thread(Option<JoinHandle<()>>),
+    Pool(WorkerPool),
+}
+
+// The worker's side of a Buffer
+struct Producer<S>
+where
+    S: Source,
+{
+    source: S,
+    channels: usize,
+    shared: Arc<BufferShared>,
+    returned: SyncSender<S>,
+}
+
+impl<S> Buffer<S>
+where
+    S: Source + Send + 'static,
+{
+    /// Creates a new Buffer with the given source and the default buffer size (currently 4800, but this may change.)
+    /// Use with_capacity() instead to specify a custom buffer size. Buffer size cannot be changed after creation.
+    pub fn new(source: S) -> Self {
+        Self::with_capacity(source, DEF_BUFFER_SIZE)
+    }
+
+    /// Creates a new Buffer with the given source and internal buffer capacity.
+    /// Buffer capacity will never change (and thus, cannot be changed) after creation. It's rounded up to a whole
+    /// number of frames.
+    pub fn with_capacity(source: S, capacity: usize) -> Self {
+        Self::with_adaptive_capacity(source, capacity, capacity)
+    }
+
+    /// Creates a new Buffer with the given source and initial capacity, which doubles whenever the Buffer has run
+    /// out a few times, up to `max_capacity`. Both are rounded up to a whole number of frames.
+    ///
+    /// Growing means more filter delay, but no more glitches once it's big enough. Memory for the largest capacity is
+    /// allocated up front, so growing never allocates.
+    pub fn with_adaptive_capacity(source: S, capacity: usize, max_capacity: usize) -> Self {
+        Self::with_options(source, BufferOptions::new().capacity(capacity).adaptive(max_capacity))
+    }
+
+    /// Creates a new Buffer with the given source and options.
+    pub fn with_options(source: S, options: BufferOptions) -> Self {
+        let channel_count = source.channel_count();
+        let frames = |samples: usize| samples.max(1).div_ceil(channel_count) * channel_count;
+        let capacity = frames(options.capacity);
+        let max_capacity = frames(options.max_capacity.unwrap_or(capacity)).max(capacity);
+        let shared = Arc::new(BufferShared {
+            ring: Ring::new(max_capacity),
+            capacity: AtomicUsize::new(capacity),
+            underruns: AtomicU64::new(0),
+            silence: AtomicU64::new(0),
+            low_water: AtomicUsize::new(usize::MAX),
+            high_water: AtomicUsize::new(0),
+            fill_time: AtomicU64::new(0),
+            max_fill_time: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            return_source: AtomicBool::new(false),
+        });
+
+        let (sender, returned) = mpsc::sync_channel(1);
+        let mut producer = Producer { source, channels: channel_count, shared: shared.clone(), returned: sender };
+        producer.fill();
+        let worker = match options.pool {
+            Some(pool) => {
+                pool.add(Box::new(producer));
+                Worker::Pool(pool)
+            },
+            None => {
+                let priority = options.priority;
+                Worker::Thread(Some(thread::spawn(move || {
+                    pool::set_priority(priority);
+                    producer.run();
+                })))
+            },
+        };
+
+        Self {
+            channel_count,
+            shared,
+            worker,
+            returned,
+            drop_timeout: options.drop_timeout,
+            closed: false,
+            max_capacity,
+            underruns: 0,
+        }
+    }
+
+    /// Returns a handle for watching this Buffer's statistics from another thread.
+    pub fn stats(&self) -> BufferStats {
+        BufferStats(self.shared.clone())
+    }
+
+    /// Stops the worker and returns the Source, waiting for the worker to finish with it first. If the Buffer has its
+    /// own thread, the thread has exited by the time this returns.
+    ///
+    /// Anything left in the buffer is thrown away, so the Source carries on from wherever the worker had got up to,
+    /// not from where playback had. Returns None if the Source panicked on the worker thread.
+    pub fn close(mut self) -> Option<S> {
+        self.shut_down(None)
+    }
+
+    /// Like `close()`, but gives up waiting after the given timeout, in which case the worker drops the Source
+    /// whenever it's finished with it, and this returns None.
+    pub fn close_timeout(mut self, timeout: Duration) -> Option<S> {
+        self.shut_down(Some(timeout))
+    }
+}
+
+impl<S> Source for Buffer<S>
+where
+    S: Source + Send + 'static,
+{
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        // Check this first: once it's set, everything the Source wrote is already in the ring
+        let finished = self.shared.finished.load(Ordering::Acquire);
+        let ring = &self.shared.ring;
+        let available = ring.available();
+        if !finished {
+            self.shared.low_water.fetch_min(available, Ordering::Relaxed);
+            self.shared.high_water.fetch_max(available, Ordering::Relaxed);
+        }
+
+        let mut silence = 0;
+        let written = if available >= buffer.len() {
+            ring.read(buffer);
+            buffer.len()
+        } else if finished {
+            ring.read(&mut buffer[..available]);
+            available
+        } else {
+            // The worker hasn't kept up, so play what there is, in whole frames, and fill the gap with silence
+            let count = available - available % self.channel_count;
+            let (data, gap) = buffer.split_at_mut(count);
+            ring.read(data);
+            gap.iter_mut().for_each(|s| *s = 0.0);
+            silence = gap.len();
+            buffer.len()
+        };
+
+        if silence > 0 {
+            self.underrun(silence);
+        }
+        if !finished && self.shared.ring.available() < self.shared.capacity.load(Ordering::Relaxed) / 2 {
+            self.worker.wake();
+        }
+        written
+    }
+
+    fn channel_count(&self) -> usize {
+        self.channel_count
+    }
+}
+
+impl<S> Buffer<S>
+where
+    S: Source + Send + 'static,
+{
+    // Tells the worker to stop and send the Source back, and waits for it
+    fn shut_down(&mut self, timeout: Option<Duration>) -> Option<S> {
+        self.closed = true;
+        self.shared.return_source.store(true, Ordering::Relaxed);
+        self.shared.closed.store(true, Ordering::Release);
+        self.worker.wake();
+
+        let source = match timeout {
+            Some(timeout) => self.returned.recv_timeout(timeout).ok(),
+            None => self.returned.recv().ok(),
+        };
+        if let Worker::Thread(thread) = &mut self.worker {
+            // The thread exits straight after sending the Source, or it's already gone if it panicked
+            if source.is_some() || timeout.is_none() {
+                if let Some(thread) = thread.take() {
+                    let _ = thread.join();
+                }
+            }
+        }
+        source
+    }
+
+    // Records an underrun, and grows the capacity if it's adaptive and this keeps happening
+    fn underrun(&mut self, silence: usize) {
+        self.shared.underruns.fetch_add(1, Ordering::Relaxed);
+        self.shared.silence.fetch_add(silence as u64, Ordering::Relaxed);
+
+        self.underruns += 1;
+        let capacity = self.shared.capacity.load(Ordering::Relaxed);
+        if self.underruns >= UNDERRUNS_BEFORE_GROWTH && capacity < self.max_capacity {
+            self.shared.capacity.store((capacity * 2).min(self.max_capacity), Ordering::Relaxed);
+            self.underruns = 0;
+        }
+    }
+}
+
+impl BufferOptions {
+    /// Creates a set of default options: the default capacity (currently 4800 samples, but this may change), which
+    /// never grows, filled by a dedicated thread at normal priority.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the Buffer's capacity in samples. It's rounded up to a whole number of frames.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Lets the capacity double whenever the Buffer has run out a few times, up to `max_capacity`. See
+    /// `Buffer::with_adaptive_capacity()`.
+    pub fn adaptive(mut self, max_capacity: usize) -> Self {
+        self.max_capacity = Some(max_capacity);
+        self
+    }
+
+    /// Keeps the Buffer topped up with one of the pool's threads instead of a thread of its own.
+    pub fn pool(mut self, pool: &WorkerPool) -> Self {
+        self.pool = Some(pool.clone());
+        self
+    }
+
+    /// Sets the priority of the Buffer's own thread. This has no effect if it's using a pool: set the pool's priority
+    /// when creating it instead.
+    pub fn priority(mut self, priority: ThreadPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Makes dropping the Buffer wait, for up to the given timeout, for the worker to finish with the Source, so that
+    /// the Source is dropped before the drop returns, on the same thread. By default, dropping doesn't wait, and the
+    /// Source is dropped by the worker. Don't set this on a Buffer which will be dropped on the audio thread.
+    pub fn drop_timeout(mut self, timeout: Duration) -> Self {
+        self.drop_timeout = Some(timeout);
+        self
+    }
+}
+
+impl Default for BufferOptions {
+    fn default() -> Self {
+        Self {
+            capacity: DEF_BUFFER_SIZE,
+            max_capacity: None,
+            pool: None,
+            priority: ThreadPriority::Normal,
+            drop_timeout: None,
+        }
+    }
+}
+
+impl BufferStats {
+    /// Returns how many times the Buffer has run out of samples and had to play silence.
+    pub fn underruns(&self) -> u64 {
+        self.0.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many samples of silence have been played because the Buffer ran out.
+    pub fn silence_inserted(&self) -> u64 {
+        self.0.silence.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many samples are waiting to be played right now.
+    pub fn fill_level(&self) -> usize {
+        self.0.ring.available()
+    }
+
+    /// Returns how many samples the Buffer currently holds at most. This only changes in adaptive Buffers.
+    pub fn capacity(&self) -> usize {
+        self.0.capacity.load(Ordering::Relaxed)
+    }
+
+    /// Returns the lowest fill level seen since the Buffer was created or the marks were reset, or None if nothing's
+    /// been read since then.
+    pub fn low_water_mark(&self) -> Option<usize> {
+        Some(self.0.low_water.load(Ordering::Relaxed)).filter(|&level| level != usize::MAX)
+    }
+
+    /// Returns the highest fill level seen since the Buffer was created or the marks were reset.
+    pub fn high_water_mark(&self) -> usize {
+        self.0.high_water.load(Ordering::Relaxed)
+    }
+
+    /// Starts the low and high water marks over from the next read.
+    pub fn reset_water_marks(&self) {
+        self.0.low_water.store(usize::MAX, Ordering::Relaxed);
+        self.0.high_water.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns how long the worker took to get samples from the Source the last time it topped the Buffer up.
+    pub fn fill_time(&self) -> Duration {
+        Duration::from_nanos(self.0.fill_time.load(Ordering::Relaxed))
+    }
+
+    /// Returns the longest the worker has ever taken to top the Buffer up.
+    pub fn max_fill_time(&self) -> Duration {
+        Duration::from_nanos(self.0.max_fill_time.load(Ordering::Relaxed))
+    }
+}
+
+impl<S> Drop for Buffer<S>
+where
+    S: Source + Send + 'static,
+{
+    fn drop(&mut self) {
+        if self.closed {
+            return
+        }
+        match self.drop_timeout {
+            Some(timeout) => mem::drop(self.shut_down(Some(timeout))),
+            None => {
+                self.shared.closed.store(true, Ordering::Release);
+                self.worker.wake();
+            },
+        }
+    }
+}
+
+impl Worker {
+    fn wake(&self) {
+        match self {
+            Self::Thread(thread) => thread.iter().for_each(|thread| thread.thread().unpark()),
+            Self::Pool(pool) => pool.wake(),
+        }
+    }
+}
+
+impl<S> Producer<S>
+where
+    S: Source,
+{
+    // Keeps the ring topped up until the Buffer is dropped or closed. The Source is kept after it ends, in case the
+    // Buffer is closed to get it back, but not if it panics.
+    fn run(mut self) {
+        while !self.is_closed() {
+            if self.shared.finished.load(Ordering::Relaxed) {
+                thread::park_timeout(WORKER_TIMEOUT);
+                continue
+            }
+            match panic::catch_unwind(AssertUnwindSafe(|| self.fill())) {
+                Ok(true) => (),
+                Ok(false) => thread::park_timeout(WORKER_TIMEOUT),
+                Err(_) => {
+                    self.abandon();
+                    return
+                },
+            }
+        }
+        self.retire();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    // Sends the Source back if the Buffer asked for it, or drops it otherwise. If the Buffer gave up waiting, sending
+    // fails and the Source is dropped here anyway.
+    fn retire(self) {
+        if self.shared.return_source.load(Ordering::Relaxed) {
+            let _ = self.returned.send(self.source);
+        }
+    }
+
+    // Gives up on a Source which has panicked. Whatever it wrote before that is still played, and then the Buffer ends,
+    // so it doesn't play silence forever.
+    fn abandon(self) {
+        self.shared.finished.store(true, Ordering::Release);
+    }
+
+    // Writes as many whole frames as will fit into the ring. Returns false if there was no room for any.
+    fn fill(&mut self) -> bool {
+        let ring = &self.shared.ring;
+        let capacity = self.shared.capacity.load(Ordering::Relaxed);
+        let free = capacity.saturating_sub(ring.available());
+        let free = free - free % self.channels;
+        if free == 0 {
+            return false
+        }
+
+        // SAFETY: only the producer calls this, and it only asks for space the consumer has already finished with
+        let (first, second) = unsafe { ring.free_space(free) };
+        let start = Instant::now();
+        let mut written = self.source.write_samples(first);
+        let mut ended = written < first.len();
+        if !ended && !second.is_empty() {
+            let count = self.source.write_samples(second);
+            written += count;
+            ended = count < second.len();
+        }
+        let time = start.elapsed().as_nanos() as u64;
+        self.shared.fill_time.store(time, Ordering::Relaxed);
+        self.shared.max_fill_time.fetch_max(time, Ordering::Relaxed);
+
+        ring.commit(written);
+        if ended {
+            self.shared.finished.store(true, Ordering::Release);
+        }
+        true
+    }
+}
+
+impl<S> Job for Producer<S>
+where
+    S: Source + Send,
+{
+    fn fullness(&self) -> Option<f32> {
+        if self.is_closed() {
+            None
+        } else if self.shared.finished.load(Ordering::Relaxed) {
+            // Nothing more to fill, but the Source is kept until the Buffer's closed
+            Some(1.0)
+        } else {
+            Some(self.shared.ring.available() as f32 / self.shared.capacity.load(Ordering::Relaxed) as f32)
+        }
+    }
+
+    fn fill(&mut self) {
+        Producer::fill(self);
+    }
+
+    fn retire(self: Box<Self>) {
+        Producer::retire(*self);
+    }
+
+    fn abandon(self: Box<Self>) {
+        Producer::abandon(*self);
+    }
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Self {
+        Self {
+            data: (0..capacity).map(|_| UnsafeCell::new(0.0)).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    // How many samples are waiting to be read
+    fn available(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    // Moves samples from the front of the ring into the output. Only the consumer may call this, and the output must
+    // be no longer than `available()`.
+    fn read(&self, output: &mut [Sample]) {
+        let head = self.head.load(Ordering::Relaxed);
+        let start = head % self.capacity();
+        let first = output.len().min(self.capacity() - start);
+        let (output1, output2) = output.split_at_mut(first);
+        // SAFETY: the producer doesn't write to anything between head and tail, and the output is no longer than that
+        unsafe {
+            output1.copy_from_slice(self.slice(start, first));
+            output2.copy_from_slice(self.slice(0, output2.len()));
+        }
+        self.head.store(head.wrapping_add(output.len()), Ordering::Release);
+    }
+
+    // Returns the free space at the back of the ring, up to `len` samples, as two slices in case it wraps around.
+    // SAFETY: only the producer may call this, `len` must be no more than the free space, and the slices must not be
+    // used after `commit()`.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn free_space(&self, len: usize) -> (&mut [Sample], &mut [Sample]) {
+        let start = self.tail.load(Ordering::Relaxed) % self.capacity();
+        let first = len.min(self.capacity() - start);
+        unsafe { (self.slice(start, first), self.slice(0, len - first)) }
+    }
+
+    // Makes samples written to the free space available to the consumer
+    fn commit(&self, len: usize) {
+        let tail = self.tail.load(Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(len), Ordering::Release);
+    }
+
+    // SAFETY: the caller must have exclusive access to the given range
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn slice(&self, start: usize, len: usize) -> &mut [Sample] {
+        // UnsafeCell<Sample> has the same layout as Sample
+        unsafe { slice::from_raw_parts_mut(self.data.as_ptr().add(start) as *mut Sample, len) }
+    }
+}
+
+// SAFETY: the producer and consumer only ever touch separate parts of the data, as described above
+unsafe impl Sync for Ring {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Buffer, BufferOptions, Ring, WorkerPool};
+    use crate::{Sample, Source};
+    use std::{
+        sync::{Arc, Mutex},
+        thread::{self, ThreadId},
+        time::{Duration, Instant},
+    };
+
+    // Counts up from 0 until it reaches `end`, and records which thread it's dropped on
+    struct Counter {
+        next: u32,
+        end: u32,
+        dropped_on: Arc<Mutex<Option<ThreadId>>>,
+    }
+
+    impl Counter {
+        fn new(end: u32) -> Self {
+            Self { next: 0, end, dropped_on: Arc::default() }
+        }
+    }
+
+    impl Source for Counter {
+        fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+            let count = buffer.len().min((self.end - self.next) as usize);
+            for s in &mut buffer[..count] {
+                *s = self.next as Sample;
+                self.next += 1;
+            }
+            count
+        }
+
+        fn channel_count(&self) -> usize {
+            1
+        }
+    }
+
+    impl Drop for Counter {
+        fn drop(&mut self) {
+            *self.dropped_on.lock().unwrap() = Some(thread::current().id());
+        }
+    }
+
+    // Plays ones until it's written `left` samples, then panics
+    struct Faulty {
+        left: usize,
+    }
+
+    impl Source for Faulty {
+        fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+            assert!(buffer.len() <= self.left, "out of samples");
+            buffer.iter_mut().for_each(|s| *s = 1.0);
+            self.left -= buffer.len();
+            buffer.len()
+        }
+
+        fn channel_count(&self) -> usize {
+            1
+        }
+    }
+
+    // Reads from the Buffer until it ends, failing if that takes too long
+    fn play_to_end<S: Source + Send>(buffer: &mut Buffer<S>) {
+        let start = Instant::now();
+        let mut output = [0.0; 16];
+        while buffer.write_samples(&mut output) == output.len() {
+            assert!(start.elapsed() < Duration::from_secs(5), "the Buffer didn't end");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(buffer.write_samples(&mut output), 0);
+    }
+
+    // Writes samples to the back of the ring, as the worker would
+    fn write(ring: &Ring, samples: &[Sample]) {
+        assert!(samples.len() <= ring.capacity() - ring.available());
+        // SAFETY: there's no other producer, and the length was checked against the free space
+        let (first, second) = unsafe { ring.free_space(samples.len()) };
+        let (samples1, samples2) = samples.split_at(first.len());
+        first.copy_from_slice(samples1);
+        second.copy_from_slice(samples2);
+        ring.commit(samples.len());
+    }
+
+    fn read(ring: &Ring, len: usize) -> Vec<Sample> {
+        let mut output = vec![0.0; len];
+        ring.read(&mut output);
+        output
+    }
+
+    #[test]
+    fn ring_reads_in_write_order() {
+        let ring = Ring::new(8);
+        write(&ring, &[1.0, 2.0, 3.0]);
+        write(&ring, &[4.0]);
+        assert_eq!(read(&ring, 2), [1.0, 2.0]);
+        assert_eq!(read(&ring, 2), [3.0, 4.0]);
+    }
+
+    #[test]
+    fn ring_fills_and_empties() {
+        let ring = Ring::new(4);
+        assert_eq!(ring.available(), 0);
+        write(&ring, &[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(ring.available(), ring.capacity());
+        assert_eq!(read(&ring, 4), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(ring.available(), 0);
+    }
+
+    #[test]
+    fn ring_wraps_around() {
+        // Writing three at a time into five samples moves the ends round the ring by a different amount each time
+        let ring = Ring::new(5);
+        let mut next = 0.0;
+        for _ in 0..100 {
+            let samples = [next, next + 1.0, next + 2.0];
+            write(&ring, &samples);
+            assert_eq!(read(&ring, 3), samples);
+            next += 3.0;
+        }
+        assert_eq!(ring.available(), 0);
+    }
+
+    #[test]
+    fn ring_passes_every_sample_between_threads() {
+        const SAMPLES: u32 = if cfg!(miri) { 2000 } else { 200_000 };
+
+        let ring = Arc::new(Ring::new(64));
+        let producer = {
+            let ring = ring.clone();
+            thread::spawn(move || {
+                let mut next = 0;
+                while next < SAMPLES {
+                    // Vary the chunk size so that writes wrap around at different places
+                    let free = ring.capacity() - ring.available();
+                    let len = free.min(next as usize % 17 + 1).min((SAMPLES - next) as usize);
+                    let samples: Vec<_> = (next..next + len as u32).map(|i| i as Sample).collect();
+                    write(&ring, &samples);
+                    next += len as u32;
+                    if len == 0 {
+                        thread::yield_now();
+                    }
+                }
+            })
+        };
+
+        let mut next = 0;
+        while next < SAMPLES {
+            let len = ring.available().min(next as usize % 13 + 1);
+            for s in read(&ring, len) {
+                assert_eq!(s, next as Sample);
+                next += 1;
+            }
+            if len == 0 {
+                thread::yield_now();
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(ring.available(), 0);
+    }
+
+    #[test]
+    fn buffer_plays_whole_source_then_ends() {
+        // The Source fits in the Buffer, so it's all there as soon as the Buffer's created
+        let mut buffer = Buffer::with_capacity(Counter::new(1000), 4800);
+        let mut output = vec![0.0; 300];
+        let mut played = Vec::new();
+        loop {
+            let written = buffer.write_samples(&mut output);
+            played.extend_from_slice(&output[..written]);
+            if written < output.len() {
+                break
+            }
+        }
+        assert!(played.into_iter().eq((0..1000).map(|i| i as Sample)));
+        assert_eq!(buffer.write_samples(&mut output), 0);
+        assert_eq!(buffer.stats().underruns(), 0);
+    }
+
+    #[test]
+    fn closing_buffer_returns_source_with_samples_left() {
+        let mut buffer = Buffer::with_capacity(Counter::new(u32::MAX), 64);
+        let mut output = [0.0; 16];
+        buffer.write_samples(&mut output);
+        assert_eq!(output[15], 15.0);
+
+        // The worker read ahead of what was played
+        let source = buffer.close().unwrap();
+        assert!(source.next >= 64);
+    }
+
+    #[test]
+    fn dropped_buffer_drops_source_on_worker() {
+        let source = Counter::new(u32::MAX);
+        let dropped_on = source.dropped_on.clone();
+        let mut buffer = Buffer::with_capacity(source, 64);
+        buffer.write_samples(&mut [0.0; 16]);
+        drop(buffer);
+
+        let start = Instant::now();
+        while dropped_on.lock().unwrap().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5), "the Source wasn't dropped");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_ne!(*dropped_on.lock().unwrap(), Some(thread::current().id()));
+    }
+
+    #[test]
+    fn panicking_source_ends_pooled_buffer() {
+        let pool = WorkerPool::new(1, Default::default());
+        let mut buffer = Buffer::with_options(Faulty { left: 100 }, BufferOptions::new().capacity(64).pool(&pool));
+        play_to_end(&mut buffer);
+    }
+
+    #[test]
+    fn panicking_source_ends_buffer() {
+        let mut buffer = Buffer::with_capacity(Faulty { left: 100 }, 64);
+        play_to_end(&mut buffer);
+    }
+}
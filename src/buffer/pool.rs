@@ -0,0 +1,185 @@
+use super::WORKER_TIMEOUT;
+use std::{
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, Thread},
+};
+
+// How full a pooled Buffer can be before it's worth topping up, as a fraction of its capacity
+const DUE_LEVEL: f32 = 0.75;
+
+/// A set of worker threads shared between any number of Buffers, which keep each of them topped up from its Source.
+///
+/// Without a pool, every Buffer gets its own worker thread, which is wasteful when there are lots of them. A pool's
+/// threads always top up whichever Buffer is emptiest first, so one which is about to run out isn't kept waiting by
+/// one which has plenty left. Pass one to `BufferOptions::pool()` to use it.
+///
+/// WorkerPools are cheap to clone, and every clone refers to the same threads. The threads stop once every clone has
+/// been dropped, along with every Buffer using them.
+#[derive(Clone)]
+pub struct WorkerPool(Arc<PoolInner>);
+
+/// How urgently the operating system should run a worker thread.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ThreadPriority {
+    /// The same priority as any other thread.
+    #[default]
+    Normal,
+
+    /// A real-time priority, at the lowest level, so the thread runs ahead of ordinary threads but still behind the
+    /// audio thread. This needs permission from the operating system, and is only supported on Unix-like systems
+    /// so far: anywhere else, or if permission is denied, the thread runs at normal priority instead.
+    High,
+}
+
+/// Something a pool keeps topped up. This is the worker's side of a Buffer.
+pub(super) trait Job: Send {
+    /// Returns how full the Buffer is, from 0 (empty) to 1 (full), or None if it's finished with because it's been
+    /// dropped or closed.
+    fn fullness(&self) -> Option<f32>;
+
+    /// Tops the Buffer up from its Source.
+    fn fill(&mut self);
+
+    /// Hands the Source back to the Buffer, or drops it. Called once the Buffer's finished with.
+    fn retire(self: Box<Self>);
+
+    /// Ends the Buffer where it is and drops the Source. Called if the Source panics.
+    fn abandon(self: Box<Self>);
+}
+
+// Dropping the last WorkerPool (Buffers using the pool hold one each) stops the threads
+struct PoolInner {
+    shared: Arc<PoolShared>,
+    threads: Vec<Thread>,
+}
+
+struct PoolShared {
+    // Every Buffer the pool is servicing, except any which are being topped up right now
+    jobs: Mutex<Vec<Box<dyn Job>>>,
+    stopping: AtomicBool,
+}
+
+impl WorkerPool {
+    /// Creates a pool with the given number of threads (at least 1), running at the given priority.
+    pub fn new(threads: usize, priority: ThreadPriority) -> Self {
+        let shared = Arc::new(PoolShared { jobs: Mutex::new(Vec::new()), stopping: AtomicBool::new(false) });
+        let threads = (0..threads.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    set_priority(priority);
+                    shared.run();
+                })
+                .thread()
+                .clone()
+            })
+            .collect();
+        Self(Arc::new(PoolInner { shared, threads }))
+    }
+
+    /// Returns how many threads the pool has.
+    pub fn thread_count(&self) -> usize {
+        self.0.threads.len()
+    }
+
+    // Starts servicing a Buffer
+    pub(super) fn add(&self, job: Box<dyn Job>) {
+        self.0.shared.jobs.lock().unwrap_or_else(PoisonError::into_inner).push(job);
+        self.wake();
+    }
+
+    // Lets the threads know a Buffer needs topping up. This never blocks, so it's safe to call from the audio thread.
+    pub(super) fn wake(&self) {
+        self.0.threads.iter().for_each(Thread::unpark);
+    }
+}
+
+impl Default for WorkerPool {
+    /// Creates a pool with one thread for each of the system's processors, up to 4, at normal priority.
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map_or(1, |count| count.get().min(4));
+        Self::new(threads, ThreadPriority::Normal)
+    }
+}
+
+impl Drop for PoolInner {
+    fn drop(&mut self) {
+        self.shared.stopping.store(true, Ordering::Release);
+        self.threads.iter().for_each(Thread::unpark);
+    }
+}
+
+impl PoolShared {
+    fn run(&self) {
+        while !self.stopping.load(Ordering::Acquire) {
+            match self.take_job() {
+                Some(mut job) => {
+                    // A Source which panics is given up on, rather than taking the thread down with it
+                    if panic::catch_unwind(AssertUnwindSafe(|| job.fill())).is_ok() {
+                        self.jobs.lock().unwrap_or_else(PoisonError::into_inner).push(job);
+                    } else {
+                        job.abandon();
+                    }
+                },
+                None => thread::park_timeout(WORKER_TIMEOUT),
+            }
+        }
+
+        // Retire anything left over here, so that no Source is dropped wherever the last reference to the pool happens
+        // to be, which could be the audio thread. Any job another thread is still filling is retired by that thread.
+        let jobs = mem::take(&mut *self.jobs.lock().unwrap_or_else(PoisonError::into_inner));
+        jobs.into_iter().for_each(|job| job.retire());
+    }
+
+    // Takes the emptiest Buffer which is due to be topped up, if there is one, and retires any which are finished with
+    fn take_job(&self) -> Option<Box<dyn Job>> {
+        let mut finished = Vec::new();
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap_or_else(PoisonError::into_inner);
+            let mut most_urgent: Option<(usize, f32)> = None;
+            let mut i = 0;
+            while i < jobs.len() {
+                match jobs[i].fullness() {
+                    None => finished.push(jobs.swap_remove(i)),
+                    Some(fullness) => {
+                        let more_urgent = match most_urgent {
+                            Some((_, lowest)) => fullness < lowest,
+                            None => true,
+                        };
+                        if fullness <= DUE_LEVEL && more_urgent {
+                            most_urgent = Some((i, fullness));
+                        }
+                        i += 1;
+                    },
+                }
+            }
+            most_urgent.map(|(i, _)| jobs.swap_remove(i))
+        };
+
+        // Sources are dropped here rather than while the lock is held, in case that takes a while
+        finished.into_iter().for_each(|job| job.retire());
+        job
+    }
+}
+
+// Sets the calling thread's priority, if the system allows it
+pub(super) fn set_priority(priority: ThreadPriority) {
+    #[cfg(unix)]
+    if priority == ThreadPriority::High {
+        // SAFETY: sched_param is plain data, and pthread_setschedparam only reads it. Failure leaves the thread as
+        // it was.
+        unsafe {
+            let mut param: libc::sched_param = std::mem::zeroed();
+            param.sched_priority = libc::sched_get_priority_min(libc::SCHED_FIFO);
+            libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param);
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = priority;
+}
@@ -0,0 +1,175 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{self, AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// A stream's playback clock, which says which of the Source's frames is being heard at any moment. Get one with
+/// `OutputStream::clock()`.
+///
+/// Frames are counted at the Source's sample rate (`OutputStream::sample_rate`), from the first frame the Source
+/// rendered, and only while it's playing, so they stop while the stream is paused. To convert between times and
+/// frames on the clock of a Mixer the stream is playing, for scheduling its voices, pass the StreamClock to
+/// `MixerHandle::frame_at_instant()` or `MixerHandle::instant_at_frame()`.
+///
+/// The audio thread updates the clock each time it renders a buffer, and the time in between is estimated from the
+/// sample rate. The latency comes from the backend's timestamps; backends which don't give any are assumed to play
+/// each buffer as soon as the previous one has finished. Like the other handles, StreamClocks are cheap to clone,
+/// can be sent between threads, and never block.
+#[derive(Clone)]
+pub struct StreamClock(pub(crate) Arc<ClockShared>);
+
+// What the audio thread knew when it rendered its last buffer. This is kept behind a sequence lock, so that it's
+// always read as a whole without the audio thread ever waiting: the sequence is odd while it's being written, and
+// readers try again if it was odd or changed while they were reading.
+pub(crate) struct ClockShared {
+    epoch: Instant,
+    sample_rate: u32,
+    sequence: AtomicU64,
+
+    // When the buffer was rendered (in nanoseconds since the epoch), how long until it'd be heard, the frame it
+    // started with, and whether it was playing the Source at all
+    rendered_at: AtomicU64,
+    latency: AtomicU64,
+    frame: AtomicU64,
+    running: AtomicBool,
+
+    // Frames rendered so far, and the highest playing frame reported, which keeps reports from going backwards
+    frames: AtomicU64,
+    reported: AtomicU64,
+}
+
+#[derive(Clone, Copy)]
+struct Snapshot {
+    rendered_at: Duration,
+    latency: Duration,
+    frame: u64,
+    running: bool,
+}
+
+impl StreamClock {
+    /// Returns the sample rate frames are counted at.
+    pub fn sample_rate(&self) -> u32 {
+        self.0.sample_rate
+    }
+
+    /// Returns how many frames have been rendered so far. These are ahead of what can be heard by the latency.
+    pub fn frames_rendered(&self) -> u64 {
+        self.0.frames.load(Ordering::Acquire)
+    }
+
+    /// Returns how long it takes for rendered audio to be heard, as last estimated.
+    pub fn latency(&self) -> Duration {
+        self.0.read().latency
+    }
+
+    /// Returns when the most recently rendered buffer will start being heard, or None if nothing's been rendered.
+    pub fn playback_time(&self) -> Option<Instant> {
+        let snapshot = self.0.read();
+        (self.frames_rendered() > 0).then(|| self.0.epoch + snapshot.rendered_at + snapshot.latency)
+    }
+
+    /// Returns the frame being heard right now. This never goes backwards, even if the estimate does.
+    pub fn playing_frame(&self) -> u64 {
+        let frame = self.frame_at(Instant::now());
+        self.0.reported.fetch_max(frame, Ordering::Relaxed).max(frame)
+    }
+
+    /// Returns the frame that was heard, or is expected to be heard, at the given time. Times before the first frame
+    /// give 0. While the stream is paused, every time from now on gives the frame it paused at.
+    pub fn frame_at(&self, time: Instant) -> u64 {
+        self.0.frame_at(time)
+    }
+
+    /// Returns the time the given frame was heard, or is expected to be heard if the stream keeps playing.
+    pub fn time_at(&self, frame: u64) -> Instant {
+        self.0.time_at(frame)
+    }
+}
+
+impl ClockShared {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            epoch: Instant::now(),
+            sample_rate,
+            sequence: AtomicU64::new(0),
+            rendered_at: AtomicU64::new(0),
+            latency: AtomicU64::new(0),
+            frame: AtomicU64::new(0),
+            running: AtomicBool::new(false),
+            frames: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns how many frames have been rendered so far.
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Acquire)
+    }
+
+    /// Returns the frame heard at the given time. See `StreamClock::frame_at()`.
+    pub fn frame_at(&self, time: Instant) -> u64 {
+        let snapshot = self.read();
+        let start = self.epoch + snapshot.rendered_at + snapshot.latency;
+        let rate = f64::from(self.sample_rate);
+        if time >= start {
+            if snapshot.running {
+                snapshot.frame + (time.duration_since(start).as_secs_f64() * rate) as u64
+            } else {
+                snapshot.frame
+            }
+        } else {
+            snapshot.frame.saturating_sub((start.duration_since(time).as_secs_f64() * rate).ceil() as u64)
+        }
+    }
+
+    /// Returns the time the given frame is heard. See `StreamClock::time_at()`.
+    pub fn time_at(&self, frame: u64) -> Instant {
+        let snapshot = self.read();
+        let start = self.epoch + snapshot.rendered_at + snapshot.latency;
+        let rate = f64::from(self.sample_rate);
+        if frame >= snapshot.frame {
+            start + Duration::from_secs_f64((frame - snapshot.frame) as f64 / rate)
+        } else {
+            let before = Duration::from_secs_f64((snapshot.frame - frame) as f64 / rate);
+            start.checked_sub(before).unwrap_or(self.epoch)
+        }
+    }
+
+    /// Records a buffer which has just been rendered, starting with `frame` and ending before `end`. This must only be
+    /// called from one thread at a time.
+    pub fn publish(&self, latency: Duration, frame: u64, end: u64, running: bool) {
+        let rendered_at = self.epoch.elapsed().as_nanos() as u64;
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence + 1, Ordering::Relaxed);
+        atomic::fence(Ordering::Release);
+        self.rendered_at.store(rendered_at, Ordering::Relaxed);
+        self.latency.store(latency.as_nanos() as u64, Ordering::Relaxed);
+        self.frame.store(frame, Ordering::Relaxed);
+        self.running.store(running, Ordering::Relaxed);
+        self.sequence.store(sequence + 2, Ordering::Release);
+        self.frames.store(end, Ordering::Release);
+    }
+
+    fn read(&self) -> Snapshot {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            if sequence % 2 == 1 {
+                std::hint::spin_loop();
+                continue
+            }
+            let snapshot = Snapshot {
+                rendered_at: Duration::from_nanos(self.rendered_at.load(Ordering::Relaxed)),
+                latency: Duration::from_nanos(self.latency.load(Ordering::Relaxed)),
+                frame: self.frame.load(Ordering::Relaxed),
+                running: self.running.load(Ordering::Relaxed),
+            };
+            atomic::fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == sequence {
+                return snapshot
+            }
+        }
+    }
+}
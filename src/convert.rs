@@ -0,0 +1,208 @@
+//! Conversion from Samples to the other formats used by devices and .wav files.
+//!
+//! Samples are clamped to -1.0..=1.0, then scaled so that 1.0 becomes the format's largest value, -1.0 becomes its
+//! negation, and 0.0 lands exactly on the middle of unsigned formats. Values are rounded to the nearest step rather
+//! than truncated. f32 output is passed through untouched.
+//!
+//! Rounding to a whole number of steps throws away anything quieter than a step, which is audible at 16 bits or less
+//! as distortion on quiet sounds and fade-outs. A Converter can hide this with dither: a tiny amount of noise added
+//! before rounding, which turns the distortion into a faint, steady hiss. Noise shaping goes further, and moves most
+//! of that hiss up to high frequencies, where it's harder to hear.
+//!
+//! OutputStream and `wav::write()` both convert through this module.
+
+use crate::Sample;
+
+// Any seed will do, as long as it isn't 0
+const RNG_SEED: u32 = 0x9E37_79B9;
+
+/// What a Converter adds to samples before rounding them to an integer format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dither {
+    /// Nothing. Samples are just rounded.
+    None,
+
+    /// Triangular (TPDF) dither of up to one step either way. This removes the distortion entirely, in exchange for
+    /// a flat noise floor. This is the default.
+    #[default]
+    Triangular,
+
+    /// Triangular dither with first-order noise shaping, which feeds each sample's rounding error into the next one
+    /// to push the noise towards high frequencies.
+    Shaped,
+}
+
+/// A format that Samples can be converted to. This is implemented for f32, u8, i16, u16 and i32, and can't be
+/// implemented outside this crate.
+pub trait FromSample: sealed::Format {
+    /// Converts one sample, without dither.
+    fn from_sample(sample: Sample) -> Self;
+}
+
+/// Converts buffers of interleaved Samples to another format, with dither. Each channel is dithered separately.
+///
+/// A Converter carries the rounding error over from the end of each buffer for noise shaping, so the same one should
+/// be used for every buffer of a stream. Its random numbers always start from the same seed, so a new Converter given
+/// the same input always gives the same output.
+#[derive(Clone, Debug)]
+pub struct Converter {
+    dither: Dither,
+    channels: usize,
+    errors: Box<[f64]>,
+    rng: u32,
+}
+
+mod sealed {
+    pub trait Format: Copy {
+        // The value 1.0 is scaled to, or 0 for floating-point formats, which aren't scaled at all
+        const MAX: i32;
+
+        // Converts a rounded value between -MAX and MAX
+        fn from_int(value: i32) -> Self;
+    }
+}
+
+impl Converter {
+    /// Creates a Converter for buffers with the given number of channels.
+    pub fn new(channels: usize, dither: Dither) -> Self {
+        let channels = channels.max(1);
+        Self { dither, channels, errors: vec![0.0; channels].into_boxed_slice(), rng: RNG_SEED }
+    }
+
+    /// Returns the kind of dither this Converter adds.
+    pub fn dither(&self) -> Dither {
+        self.dither
+    }
+
+    /// Converts `input` into `output`. If they're different lengths, only the length of the shorter one is converted.
+    pub fn convert<T: FromSample>(&mut self, input: &[Sample], output: &mut [T]) {
+        if T::MAX == 0 || self.dither == Dither::None {
+            convert(input, output);
+            return
+        }
+
+        let max = f64::from(T::MAX);
+        for (i, (in_sample, out_sample)) in input.iter().zip(output.iter_mut()).enumerate() {
+            let target = f64::from(in_sample.clamp(-1.0, 1.0)) * max;
+            let noise = self.next_random() + self.next_random() - 1.0;
+            let value = match self.dither {
+                Dither::Shaped => {
+                    let error = &mut self.errors[i % self.channels];
+                    let wanted = target - *error;
+                    let value = (wanted + noise).round().clamp(-max, max);
+                    *error = value - wanted;
+                    value
+                },
+                _ => (target + noise).round().clamp(-max, max),
+            };
+            *out_sample = T::from_int(value as i32);
+        }
+    }
+
+    // Returns a random number from 0.0 to 1.0. A xorshift generator is plenty for noise.
+    fn next_random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        f64::from(self.rng) / f64::from(u32::MAX)
+    }
+}
+
+/// Converts `input` into `output` without dither. If they're different lengths, only the length of the shorter one
+/// is converted.
+pub fn convert<T: FromSample>(input: &[Sample], output: &mut [T]) {
+    for (in_sample, out_sample) in input.iter().zip(output.iter_mut()) {
+        *out_sample = T::from_sample(*in_sample);
+    }
+}
+
+/// Converts a sample to 24 bits without dither. 24-bit samples have no type of their own, so they're kept in the low
+/// bits of an i32.
+pub fn to_i24(sample: Sample) -> i32 {
+    to_int(sample, 8388607)
+}
+
+// Scales and rounds a sample, without dither
+#[inline(always)]
+fn to_int(sample: Sample, max: i32) -> i32 {
+    (f64::from(sample.clamp(-1.0, 1.0)) * f64::from(max)).round() as i32
+}
+
+impl FromSample for f32 {
+    #[inline(always)]
+    fn from_sample(sample: Sample) -> Self {
+        sample
+    }
+}
+
+impl FromSample for u8 {
+    #[inline(always)]
+    fn from_sample(sample: Sample) -> Self {
+        sealed::Format::from_int(to_int(sample, <Self as sealed::Format>::MAX))
+    }
+}
+
+impl FromSample for i16 {
+    #[inline(always)]
+    fn from_sample(sample: Sample) -> Self {
+        sealed::Format::from_int(to_int(sample, <Self as sealed::Format>::MAX))
+    }
+}
+
+impl FromSample for u16 {
+    #[inline(always)]
+    fn from_sample(sample: Sample) -> Self {
+        sealed::Format::from_int(to_int(sample, <Self as sealed::Format>::MAX))
+    }
+}
+
+impl FromSample for i32 {
+    #[inline(always)]
+    fn from_sample(sample: Sample) -> Self {
+        sealed::Format::from_int(to_int(sample, <Self as sealed::Format>::MAX))
+    }
+}
+
+impl sealed::Format for f32 {
+    const MAX: i32 = 0;
+
+    fn from_int(value: i32) -> Self {
+        value as f32
+    }
+}
+
+impl sealed::Format for u8 {
+    const MAX: i32 = i8::MAX as i32;
+
+    #[inline(always)]
+    fn from_int(value: i32) -> Self {
+        (value + 128) as u8
+    }
+}
+
+impl sealed::Format for i16 {
+    const MAX: i32 = i16::MAX as i32;
+
+    #[inline(always)]
+    fn from_int(value: i32) -> Self {
+        value as i16
+    }
+}
+
+impl sealed::Format for u16 {
+    const MAX: i32 = i16::MAX as i32;
+
+    #[inline(always)]
+    fn from_int(value: i32) -> Self {
+        (value + 32768) as u16
+    }
+}
+
+impl sealed::Format for i32 {
+    const MAX: i32 = i32::MAX;
+
+    #[inline(always)]
+    fn from_int(value: i32) -> Self {
+        value
+    }
+}
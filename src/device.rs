@@ -0,0 +1,151 @@
+#[cfg(feature = "cpal")]
+use crate::Error;
+#[cfg(feature = "cpal")]
+use cpal::traits::{DeviceTrait, HostTrait};
+#[cfg(feature = "cpal")]
+use std::fmt;
+use std::ops::RangeInclusive;
+
+#[cfg(feature = "cpal")]
+/// An audio API available on this system, such as ALSA, WASAPI or CoreAudio. Most systems only have one.
+/// List them with `hosts()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Host {
+    pub(crate) id: cpal::HostId,
+}
+
+#[cfg(feature = "cpal")]
+/// An audio output device, such as a pair of speakers or a headset. List them with `Host::output_devices()`,
+/// and open one with `OutputStreamBuilder::device()`.
+pub struct Device {
+    pub(crate) host: cpal::HostId,
+    pub(crate) name: String,
+    pub(crate) inner: cpal::Device,
+}
+
+/// The format of the samples a device expects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    I16,
+    U16,
+    F32,
+}
+
+/// A range of playback configurations supported by an output device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SupportedConfig {
+    /// The number of channels.
+    pub channels: u16,
+
+    /// The range of sample rates, in Hz.
+    pub sample_rates: RangeInclusive<u32>,
+
+    /// The range of buffer sizes, in frames, or None if the device can't say.
+    pub buffer_sizes: Option<RangeInclusive<u32>>,
+
+    /// The sample format.
+    pub sample_format: SampleFormat,
+}
+
+#[cfg(feature = "cpal")]
+/// Returns every audio host available on this system. The default host is always first.
+pub fn hosts() -> Vec<Host> {
+    let default = cpal::default_host().id();
+    let mut hosts: Vec<Host> = cpal::available_hosts().into_iter().map(|id| Host { id }).collect();
+    hosts.sort_by_key(|host| host.id != default);
+    hosts
+}
+
+#[cfg(feature = "cpal")]
+/// Returns the system's default audio host.
+pub fn default_host() -> Host {
+    Host { id: cpal::default_host().id() }
+}
+
+#[cfg(feature = "cpal")]
+impl Host {
+    /// Returns the name of the host's audio API, such as "ALSA".
+    pub fn name(&self) -> &'static str {
+        self.id.name()
+    }
+
+    /// Returns true if this is the system's default host.
+    pub fn is_default(&self) -> bool {
+        self.id == cpal::default_host().id()
+    }
+
+    /// Returns every output device on this host which is currently available.
+    pub fn output_devices(&self) -> Result<Vec<Device>, Error> {
+        let host = cpal::host_from_id(self.id)?;
+        let mut devices = Vec::new();
+        for inner in host.output_devices()? {
+            // A device which can't report its name has usually just been unplugged, so leave it out
+            if let Ok(name) = inner.name() {
+                devices.push(Device { host: self.id, name, inner });
+            }
+        }
+        Ok(devices)
+    }
+
+    /// Returns the host's default output device, or None if there isn't one.
+    pub fn default_output_device(&self) -> Option<Device> {
+        let inner = cpal::host_from_id(self.id).ok()?.default_output_device()?;
+        Some(Device { host: self.id, name: inner.name().ok()?, inner })
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl Device {
+    /// Returns the device's name, as shown by the operating system.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the host this device belongs to.
+    pub fn host(&self) -> Host {
+        Host { id: self.host }
+    }
+
+    /// Returns true if this is currently its host's default output device.
+    pub fn is_default(&self) -> bool {
+        self.host().default_output_device().is_some_and(|device| device.name == self.name)
+    }
+
+    /// Returns every playback configuration the device supports.
+    pub fn supported_configs(&self) -> Result<Vec<SupportedConfig>, Error> {
+        Ok(self.inner.supported_output_configs()?.map(SupportedConfig::from).collect())
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl fmt::Debug for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Device").field("host", &self.host).field("name", &self.name).finish()
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<cpal::SampleFormat> for SampleFormat {
+    fn from(format: cpal::SampleFormat) -> Self {
+        match format {
+            cpal::SampleFormat::I16 => Self::I16,
+            cpal::SampleFormat::U16 => Self::U16,
+            cpal::SampleFormat::F32 => Self::F32,
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<cpal::SupportedStreamConfigRange> for SupportedConfig {
+    fn from(range: cpal::SupportedStreamConfigRange) -> Self {
+        Self {
+            channels: range.channels(),
+            sample_rates: range.min_sample_rate().0..=range.max_sample_rate().0,
+            buffer_sizes: match range.buffer_size() {
+                cpal::SupportedBufferSize::Range { min, max } => Some(*min..=*max),
+                cpal::SupportedBufferSize::Unknown => None,
+            },
+            sample_format: range.sample_format().into(),
+        }
+    }
+}
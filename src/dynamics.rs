@@ -0,0 +1,340 @@
+use crate::Sample;
+use std::time::Duration;
+
+/// A dynamics processor which can be placed on a mix bus with `MixerHandle::set_dynamics()`.
+/// It's applied after the bus's own gain, so it sees exactly what the bus passes on to its parent.
+#[derive(Clone, Copy, Debug)]
+pub enum Dynamics {
+    /// No processing.
+    Off,
+
+    /// A look-ahead peak limiter, which never lets the signal go over its ceiling.
+    Limiter(LimiterSettings),
+
+    /// A soft clipper, which is much cheaper than a limiter but distorts anything that goes over its knee.
+    SoftClip(SoftClipSettings),
+}
+
+/// Settings for a look-ahead peak limiter.
+///
+/// The limiter delays its input by the attack time, so that it can see peaks coming and turn the gain down
+/// smoothly before they arrive, rather than clipping them.
+#[derive(Clone, Copy, Debug)]
+pub struct LimiterSettings {
+    /// The highest level the output is allowed to reach, in dBFS.
+    pub ceiling_db: f32,
+
+    /// How long the gain takes to come down before a peak. This is also the latency the limiter adds, plus one frame
+    /// if `true_peak` is set.
+    pub attack: Duration,
+
+    /// How long the gain takes to recover after a peak, measured as the time to get 63% of the way back.
+    pub release: Duration,
+
+    /// Whether to also limit peaks which occur between samples, estimated by 4x oversampling.
+    /// These can cause clipping in a DAC or later resampler even if no individual sample is over the ceiling.
+    pub true_peak: bool,
+}
+
+/// Settings for a soft clipper.
+#[derive(Clone, Copy, Debug)]
+pub struct SoftClipSettings {
+    /// The level that the output will approach but never reach, in dBFS.
+    pub ceiling_db: f32,
+
+    /// How far below the ceiling, as a fraction of it from 0.0 to 1.0, the signal starts to be squashed.
+    /// Anything quieter than this passes through untouched.
+    pub knee: f32,
+}
+
+/// Settings for ducking one mix bus whenever another bus or voice is loud, set up with `MixerHandle::set_ducking()`.
+///
+/// For example, with a threshold of -30 dB and a ratio of 4, a dialogue line peaking at -10 dB would turn the ducked
+/// bus down by 15 dB: the key is 20 dB over the threshold, and 3/4 of that is taken off.
+#[derive(Clone, Copy, Debug)]
+pub struct DuckingSettings {
+    /// The level, in dBFS, which the key signal has to reach before any ducking happens.
+    pub threshold_db: f32,
+
+    /// How strongly to duck. For every `ratio` dB the key goes over the threshold, the ducked bus is turned down by
+    /// `ratio - 1` dB. A ratio of 1 disables ducking.
+    pub ratio: f32,
+
+    /// How long the ducked bus takes to be turned down, measured as the time to get 63% of the way there.
+    pub attack: Duration,
+
+    /// How long the ducked bus takes to come back up after the key goes quiet, measured the same way.
+    pub release: Duration,
+
+    /// How long to wait after the key goes quiet before starting to release, which stops the ducked bus from
+    /// pumping back up in short pauses, such as between words.
+    pub hold: Duration,
+}
+
+// The running state of a Dynamics on a bus. Everything is allocated when this is created, not when it's run.
+pub(crate) enum Processor {
+    Limiter(Limiter),
+    SoftClip(SoftClip),
+}
+
+pub(crate) struct Limiter {
+    channels: usize,
+    ceiling: Sample,
+    release: Sample,
+    true_peak: bool,
+
+    // Input delayed by the lookahead time
+    delay: Box<[Sample]>,
+    delay_pos: usize,
+
+    // Sliding-window minimum of the required gain, stored as a monotonic queue of (frame, gain)
+    minimum: Box<[(u64, Sample)]>,
+    minimum_start: usize,
+    minimum_len: usize,
+
+    // Moving average of the windowed minimum
+    average: Box<[Sample]>,
+    average_pos: usize,
+    average_sum: f64,
+
+    // The previous frame, per channel, for estimating true peaks
+    history: Box<[[Sample; 3]]>,
+
+    frame: u64,
+    envelope: Sample,
+}
+
+pub(crate) struct SoftClip {
+    ceiling: Sample,
+    threshold: Sample,
+}
+
+/// Converts a level in decibels to a linear gain.
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Converts a linear gain to a level in decibels.
+pub fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.log10()
+}
+
+impl Default for LimiterSettings {
+    /// A ceiling of -1 dBFS, 5 ms attack, 100 ms release, no true-peak detection.
+    fn default() -> Self {
+        Self {
+            ceiling_db: -1.0,
+            attack: Duration::from_millis(5),
+            release: Duration::from_millis(100),
+            true_peak: false,
+        }
+    }
+}
+
+impl Default for DuckingSettings {
+    /// A threshold of -30 dBFS, 4:1 ratio, 20 ms attack, 500 ms release and 200 ms hold.
+    fn default() -> Self {
+        Self {
+            threshold_db: -30.0,
+            ratio: 4.0,
+            attack: Duration::from_millis(20),
+            release: Duration::from_millis(500),
+            hold: Duration::from_millis(200),
+        }
+    }
+}
+
+impl Default for SoftClipSettings {
+    /// A ceiling of 0 dBFS and a knee of 0.25.
+    fn default() -> Self {
+        Self { ceiling_db: 0.0, knee: 0.25 }
+    }
+}
+
+impl Processor {
+    pub fn new(dynamics: Dynamics, channels: usize, sample_rate: u32) -> Option<Self> {
+        match dynamics {
+            Dynamics::Off => None,
+            Dynamics::Limiter(settings) => Some(Self::Limiter(Limiter::new(settings, channels, sample_rate))),
+            Dynamics::SoftClip(settings) => Some(Self::SoftClip(SoftClip::new(settings))),
+        }
+    }
+
+    /// Processes a block of interleaved samples in place.
+    pub fn process(&mut self, buffer: &mut [Sample]) {
+        match self {
+            Self::Limiter(limiter) => limiter.process(buffer),
+            Self::SoftClip(clip) => clip.process(buffer),
+        }
+    }
+
+    /// Forgets any audio that's been seen so far, as if the processor had just been created.
+    pub fn reset(&mut self) {
+        if let Self::Limiter(limiter) = self {
+            limiter.reset();
+        }
+    }
+}
+
+impl Limiter {
+    fn new(settings: LimiterSettings, channels: usize, sample_rate: u32) -> Self {
+        let rate = f64::from(sample_rate);
+        let lookahead = ((settings.attack.as_secs_f64() * rate).round() as usize).max(1);
+
+        // An inter-sample peak is only found once the frame after it has arrived, so with true peak limiting, the
+        // input is delayed and the gain is held for one more frame, to make sure the gain is fully down for both of
+        // the frames either side of it
+        let delay = lookahead + usize::from(settings.true_peak);
+        let release_frames = (settings.release.as_secs_f64() * rate).max(1.0);
+
+        Self {
+            channels,
+            ceiling: db_to_gain(settings.ceiling_db),
+            release: (1.0 - (-1.0 / release_frames).exp()) as Sample,
+            true_peak: settings.true_peak,
+            delay: vec![0.0; delay * channels].into_boxed_slice(),
+            delay_pos: 0,
+            minimum: vec![(0, 1.0); delay + 1].into_boxed_slice(),
+            minimum_start: 0,
+            minimum_len: 0,
+            average: vec![1.0; lookahead].into_boxed_slice(),
+            average_pos: 0,
+            average_sum: lookahead as f64,
+            history: vec![[0.0; 3]; channels].into_boxed_slice(),
+            frame: 0,
+            envelope: 1.0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay.iter_mut().for_each(|s| *s = 0.0);
+        self.minimum_len = 0;
+        self.average.iter_mut().for_each(|g| *g = 1.0);
+        self.average_sum = self.average.len() as f64;
+        self.history.iter_mut().for_each(|h| *h = [0.0; 3]);
+        self.envelope = 1.0;
+    }
+
+    fn process(&mut self, buffer: &mut [Sample]) {
+        let lookahead = self.average.len();
+        let window = self.minimum.len();
+
+        for frame in buffer.chunks_exact_mut(self.channels) {
+            // Find the gain this frame needs to stay under the ceiling
+            let mut peak: Sample = 0.0;
+            for (sample, history) in frame.iter().zip(self.history.iter_mut()) {
+                peak = peak.max(sample.abs());
+                if self.true_peak {
+                    peak = peak.max(inter_sample_peak(history, *sample));
+                }
+                *history = [history[1], history[2], *sample];
+            }
+            let required = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+
+            // Hold the lowest required gain over the delay (plus this frame), so that the gain is already down by the
+            // time each peak leaves the delay line...
+            if self.minimum_len > 0 && self.frame - self.minimum[self.minimum_start].0 >= window as u64 {
+                self.minimum_start = (self.minimum_start + 1) % window;
+                self.minimum_len -= 1;
+            }
+            while self.minimum_len > 0
+                && self.minimum[(self.minimum_start + self.minimum_len - 1) % window].1 >= required
+            {
+                self.minimum_len -= 1;
+            }
+            self.minimum[(self.minimum_start + self.minimum_len) % window] = (self.frame, required);
+            self.minimum_len += 1;
+            let held = self.minimum[self.minimum_start].1;
+
+            // ...then smooth it with a moving average over the same window, which gives a linear attack ramp
+            // that never rises above the held gain...
+            self.average_sum += f64::from(held) - f64::from(self.average[self.average_pos]);
+            self.average[self.average_pos] = held;
+            self.average_pos = (self.average_pos + 1) % lookahead;
+            let smoothed = (self.average_sum / lookahead as f64) as Sample;
+
+            // ...and let it recover exponentially after the peak has passed
+            if smoothed < self.envelope {
+                self.envelope = smoothed;
+            } else {
+                self.envelope += (smoothed - self.envelope) * self.release;
+            }
+
+            // Swap this frame with the delayed one, and apply the gain to that
+            let delayed = &mut self.delay[self.delay_pos..(self.delay_pos + self.channels)];
+            for (sample, old) in frame.iter_mut().zip(delayed.iter_mut()) {
+                let out = *old * self.envelope;
+                *old = *sample;
+                // The inter-sample peak estimate isn't perfect, so make sure the ceiling is never exceeded
+                *sample = out.clamp(-self.ceiling, self.ceiling);
+            }
+            self.delay_pos = (self.delay_pos + self.channels) % self.delay.len();
+            self.frame += 1;
+        }
+    }
+}
+
+impl SoftClip {
+    fn new(settings: SoftClipSettings) -> Self {
+        let ceiling = db_to_gain(settings.ceiling_db);
+        Self { ceiling, threshold: ceiling * (1.0 - settings.knee.clamp(0.0, 1.0)) }
+    }
+
+    fn process(&mut self, buffer: &mut [Sample]) {
+        let range = self.ceiling - self.threshold;
+        for sample in buffer.iter_mut() {
+            let level = sample.abs();
+            if level > self.threshold {
+                let clipped = if range > 0.0 {
+                    self.threshold + range * ((level - self.threshold) / range).tanh()
+                } else {
+                    self.ceiling
+                };
+                *sample = clipped.copysign(*sample);
+            }
+        }
+    }
+}
+
+// Estimates the highest peak between history[1] and history[2], using Catmull-Rom interpolation at 4x oversampling
+fn inter_sample_peak(history: &[Sample; 3], next: Sample) -> Sample {
+    let [p0, p1, p2] = *history;
+    let p3 = next;
+    [0.25, 0.5, 0.75]
+        .iter()
+        .map(|t: &Sample| {
+            let t2 = t * t;
+            let t3 = t2 * t;
+            0.5 * ((2.0 * p1)
+                + (p2 - p0) * t
+                + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+                + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+        })
+        .fold(0.0, |peak: Sample, s| peak.max(s.abs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn true_peak_limiter_catches_peak_between_newest_frames() {
+        let settings = LimiterSettings {
+            ceiling_db: 0.0,
+            attack: Duration::from_micros(100),
+            true_peak: true,
+            ..Default::default()
+        };
+        let mut limiter = Limiter::new(settings, 1, 48000);
+
+        // Silence, then a quarter-rate sine whose samples all miss its peaks: every sample is at 0.99, under the
+        // ceiling, but the waveform between them reaches 1.4
+        let mut buffer = vec![0.0; 64];
+        buffer.extend([0.99, 0.99, -0.99, -0.99].iter().cycle().take(64));
+        limiter.process(&mut buffer);
+
+        let worst =
+            buffer.windows(4).fold(0.0, |worst: Sample, w| worst.max(inter_sample_peak(&[w[0], w[1], w[2]], w[3])));
+        assert!(worst <= 1.001, "inter-sample peak of {} got through", worst);
+    }
+}
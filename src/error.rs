@@ -1,14 +1,30 @@
-#[derive(Debug)]
+#[cfg(feature = "cpal")]
+use cpal::{
+    BuildStreamError, DeviceNameError, DevicesError, HostUnavailable, PlayStreamError, StreamError,
+    SupportedStreamConfigsError,
+};
+use std::fmt;
+
+/// The error type for everything in this crate. Errors from the mixer and the wav decoder have their own types,
+/// which convert into this one, so `?` can be used on any of them in a function returning `kou::Error`.
+#[derive(Clone, Debug)]
 pub enum Error {
     /// "catch-all" error type returned by CPAL in cases of unknown or unexpected errors
+    #[cfg(feature = "cpal")]
     CPALError(cpal::BackendSpecificError),
 
+    /// An error from a custom Backend which doesn't fit any of the other kinds
+    Backend(String),
+
     /// The device no longer exists (ie. it has been disabled or unplugged)
     DeviceNotAvailable,
 
     /// The device doesn't support any of the playback configurations we can use
     DeviceNotUsable,
 
+    /// The requested audio host isn't available on this system, or there's no backend to play on
+    HostUnavailable,
+
     /// An invalid argument was provided somewhere in the CPAL backend
     InvalidArgument,
 
@@ -17,4 +33,129 @@ pub enum Error {
 
     /// Occurs if adding a new Stream ID would cause an integer overflow.
     StreamIdOverflow,
+
+    /// The audio thread didn't respond in time, because the backend has stopped asking for samples
+    Timeout,
+
+    /// An error from a Mixer or MixerHandle
+    Mixer(crate::mixer::Error),
+
+    /// An error decoding a .wav file
+    #[cfg(feature = "wav")]
+    Wav(crate::wav::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "cpal")]
+            Self::CPALError(err) => write!(f, "audio backend error: {}", err.description),
+            Self::Backend(message) => write!(f, "audio backend error: {}", message),
+            Self::DeviceNotAvailable => f.write_str("the audio device is no longer available"),
+            Self::DeviceNotUsable => f.write_str("the audio device doesn't support any usable playback configuration"),
+            Self::HostUnavailable => f.write_str("the requested audio host isn't available"),
+            Self::InvalidArgument => f.write_str("an invalid argument was passed to the audio backend"),
+            Self::NoOutputDevice => f.write_str("there is no audio output device available"),
+            Self::StreamIdOverflow => f.write_str("ran out of audio stream IDs"),
+            Self::Timeout => f.write_str("timed out waiting for the audio thread"),
+            Self::Mixer(err) => write!(f, "mixer error: {}", err),
+            #[cfg(feature = "wav")]
+            Self::Wav(err) => write!(f, "wav error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "cpal")]
+            Self::CPALError(err) => Some(err),
+            Self::Mixer(err) => Some(err),
+            #[cfg(feature = "wav")]
+            Self::Wav(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<crate::mixer::Error> for Error {
+    fn from(err: crate::mixer::Error) -> Self {
+        Self::Mixer(err)
+    }
+}
+
+#[cfg(feature = "wav")]
+impl From<crate::wav::Error> for Error {
+    fn from(err: crate::wav::Error) -> Self {
+        Self::Wav(err)
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<HostUnavailable> for Error {
+    fn from(_: HostUnavailable) -> Self {
+        Self::HostUnavailable
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<DevicesError> for Error {
+    fn from(err: DevicesError) -> Self {
+        match err {
+            DevicesError::BackendSpecific { err } => Self::CPALError(err),
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<DeviceNameError> for Error {
+    fn from(err: DeviceNameError) -> Self {
+        match err {
+            DeviceNameError::BackendSpecific { err } => Self::CPALError(err),
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<SupportedStreamConfigsError> for Error {
+    fn from(err: SupportedStreamConfigsError) -> Self {
+        match err {
+            SupportedStreamConfigsError::DeviceNotAvailable => Self::DeviceNotAvailable,
+            SupportedStreamConfigsError::InvalidArgument => Self::InvalidArgument,
+            SupportedStreamConfigsError::BackendSpecific { err } => Self::CPALError(err),
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<BuildStreamError> for Error {
+    fn from(err: BuildStreamError) -> Self {
+        match err {
+            BuildStreamError::DeviceNotAvailable => Self::DeviceNotAvailable,
+            BuildStreamError::StreamConfigNotSupported => Self::DeviceNotUsable,
+            BuildStreamError::InvalidArgument => Self::InvalidArgument,
+            BuildStreamError::StreamIdOverflow => Self::StreamIdOverflow,
+            BuildStreamError::BackendSpecific { err } => Self::CPALError(err),
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<PlayStreamError> for Error {
+    fn from(err: PlayStreamError) -> Self {
+        match err {
+            PlayStreamError::DeviceNotAvailable => Self::DeviceNotAvailable,
+            PlayStreamError::BackendSpecific { err } => Self::CPALError(err),
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<StreamError> for Error {
+    fn from(err: StreamError) -> Self {
+        match err {
+            StreamError::DeviceNotAvailable => Self::DeviceNotAvailable,
+            StreamError::BackendSpecific { err } => Self::CPALError(err),
+        }
+    }
 }
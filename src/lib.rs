@@ -1,18 +1,32 @@
+pub mod backend;
 pub mod buffer;
+mod clock;
+pub mod convert;
+pub mod device;
+pub mod dynamics;
 mod error;
 pub mod mixer;
+mod queue;
+pub mod remix;
+pub mod render;
 pub mod resampler;
 pub mod source;
 mod stream;
 #[cfg(feature = "wav")]
 pub mod wav;
 
-pub use buffer::Buffer;
+pub use backend::Backend;
+pub use buffer::{Buffer, BufferStats};
+pub use clock::StreamClock;
+#[cfg(feature = "cpal")]
+pub use device::{Device, Host};
 pub use error::Error;
 pub use mixer::Mixer;
-pub use resampler::Resampler;
+pub use remix::{ChannelLayout, RemixMatrix};
+pub use render::OfflineRenderer;
+pub use resampler::{Resampler, VariableResampler};
 pub use source::Source;
-pub use stream::OutputStream;
+pub use stream::{OutputStream, OutputStreamBuilder, StreamConfig, StreamEvent};
 
 pub type Sample = f32;
 
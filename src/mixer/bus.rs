@@ -0,0 +1,225 @@
+use super::{
+    meter::{Meter, MeterShared, MeterState},
+    sidechain::Ducker,
+};
+use crate::{Sample, dynamics::Processor};
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+/// A handle to a bus in a Mixer. Every Mixer starts with a master bus, available from `MixerHandle::master()`,
+/// and more can be added as children of it with `MixerHandle::add_bus()`.
+///
+/// Each bus sums the voices routed to it and the output of its child buses, applies its own gain, then passes the
+/// result on to its parent. So "Master > Music / SFX" style volume controls can be made by creating "Music" and
+/// "SFX" buses, routing each sound to one of them, and using the gain of each bus as its volume slider.
+///
+/// Like VoiceHandles, BusHandles are cheap to clone, can be sent between threads, and never block.
+#[derive(Clone)]
+pub struct BusHandle(pub(super) Arc<BusShared>);
+
+pub(super) struct BusShared {
+    pub mixer: usize,
+    pub index: usize,
+    pub name: Box<str>,
+    gain: AtomicU32,
+    muted: AtomicBool,
+    soloed: AtomicBool,
+    paused: AtomicBool,
+    meter: Arc<MeterShared>,
+
+    // Peak output level in the last block, for use as a sidechain key
+    level: AtomicU32,
+}
+
+pub(super) struct Bus {
+    pub shared: Arc<BusShared>,
+    pub parent: usize,
+    pub buffer: Vec<Sample>,
+    pub dynamics: Option<Processor>,
+    pub ducker: Option<Ducker>,
+    pub meter: MeterState,
+
+    // Gain applied at the end of the last block, or None if the bus hasn't played yet
+    pub last_gain: Option<Sample>,
+
+    // Flags calculated at the start of each block from this bus and its relatives
+    pub paused: bool,
+    pub solo_path: bool,
+    pub solo_below: bool,
+}
+
+impl BusHandle {
+    /// Returns the name this bus was created with.
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    /// Sets the bus's linear gain, where 1.0 is unchanged. Negative values are treated as 0.
+    pub fn set_gain(&self, gain: f32) {
+        self.0.gain.store(gain.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the bus's linear gain.
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.0.gain.load(Ordering::Relaxed))
+    }
+
+    /// Mutes or unmutes the bus. A muted bus keeps playing its voices, but its output is silenced.
+    pub fn set_muted(&self, muted: bool) {
+        self.0.muted.store(muted, Ordering::Relaxed);
+    }
+
+    /// Returns true if the bus is muted.
+    pub fn is_muted(&self) -> bool {
+        self.0.muted.load(Ordering::Relaxed)
+    }
+
+    /// Solos or unsolos the bus. While any bus in the Mixer is soloed, only soloed buses, their children, and the
+    /// buses they pass through on their way to the master bus can be heard.
+    pub fn set_solo(&self, soloed: bool) {
+        self.0.soloed.store(soloed, Ordering::Relaxed);
+    }
+
+    /// Returns true if the bus is soloed.
+    pub fn is_soloed(&self) -> bool {
+        self.0.soloed.load(Ordering::Relaxed)
+    }
+
+    /// Pauses the bus. Every voice routed to it or any of its children is paused, and picks up where it left off
+    /// when the bus is resumed. Voices stay in whatever VoiceState they were in.
+    pub fn pause(&self) {
+        self.0.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resumes the bus.
+    pub fn resume(&self) {
+        self.0.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns true if the bus itself is paused. This doesn't check whether any of its parents are paused.
+    pub fn is_paused(&self) -> bool {
+        self.0.paused.load(Ordering::Relaxed)
+    }
+
+    /// Returns the bus's level meter, which measures its output after its gain, ducking and dynamics.
+    pub fn meter(&self) -> Meter {
+        Meter(self.0.meter.clone())
+    }
+}
+
+impl BusShared {
+    pub fn level(&self) -> Sample {
+        f32::from_bits(self.level.load(Ordering::Relaxed))
+    }
+
+    pub fn new(mixer: usize, index: usize, name: &str) -> Self {
+        Self {
+            mixer,
+            index,
+            name: name.into(),
+            gain: AtomicU32::new(1.0f32.to_bits()),
+            muted: AtomicBool::new(false),
+            soloed: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            meter: Arc::new(MeterShared::new()),
+            level: AtomicU32::new(0.0f32.to_bits()),
+        }
+    }
+}
+
+impl Bus {
+    pub fn new(shared: Arc<BusShared>, parent: usize, channels: usize, sample_rate: u32) -> Self {
+        let meter = MeterState::new(shared.meter.clone(), channels, sample_rate);
+        Self {
+            shared,
+            parent,
+            buffer: Vec::with_capacity(super::MAX_BLOCK_FRAMES * channels),
+            dynamics: None,
+            ducker: None,
+            meter,
+            last_gain: None,
+            paused: false,
+            solo_path: false,
+            solo_below: false,
+        }
+    }
+
+    /// Returns true if this bus is paused and has already faded out, so its voices shouldn't be played.
+    pub fn is_frozen(&self) -> bool {
+        self.paused && self.last_gain == Some(0.0)
+    }
+
+    /// Applies this bus's gain to its buffer, ramping from the gain used in the last block, runs it through the bus's
+    /// ducker and dynamics processor if it has them, and adds it onto `output`.
+    pub fn mix_into(&mut self, output: &mut [Sample], channels: usize, any_soloed: bool) {
+        let shared = &*self.shared;
+        let audible = !any_soloed || self.solo_path || self.solo_below;
+        let target = if self.paused || !audible || shared.muted.load(Ordering::Relaxed) {
+            0.0
+        } else {
+            f32::from_bits(shared.gain.load(Ordering::Relaxed))
+        };
+
+        let from = *self.last_gain.get_or_insert(target);
+        if from == 0.0 && target == 0.0 {
+            // Don't let anything left in a processor's delay line come out when the bus is heard again
+            if let Some(dynamics) = &mut self.dynamics {
+                dynamics.reset();
+            }
+            shared.level.store(0.0f32.to_bits(), Ordering::Relaxed);
+            self.meter.skip(output.len() / channels);
+            return
+        }
+        let step = (target - from) / (output.len() / channels).max(1) as Sample;
+        for (i, frame) in self.buffer.chunks_exact_mut(channels).enumerate() {
+            let gain = from + step * (i + 1) as Sample;
+            frame.iter_mut().for_each(|s| *s *= gain);
+        }
+        self.last_gain = Some(target);
+
+        if let Some(ducker) = &mut self.ducker {
+            ducker.process(&mut self.buffer, channels);
+        }
+        if let Some(dynamics) = &mut self.dynamics {
+            dynamics.process(&mut self.buffer);
+        }
+
+        let mut level: Sample = 0.0;
+        for (in_sample, out_sample) in self.buffer.iter().zip(output.iter_mut()) {
+            *out_sample += in_sample;
+            level = level.max(in_sample.abs());
+        }
+        shared.level.store(level.to_bits(), Ordering::Relaxed);
+        self.meter.process(&self.buffer);
+    }
+}
+
+pub(super) fn update_flags(buses: &mut [Bus]) -> bool {
+    // Parents always come before their children, so a forward pass can pass state down the tree...
+    for i in 0..buses.len() {
+        let (paused, soloed) =
+            (buses[i].shared.paused.load(Ordering::Relaxed), buses[i].shared.soloed.load(Ordering::Relaxed));
+        let (parent_paused, parent_solo_path) = if i == 0 {
+            (false, false)
+        } else {
+            let parent = &buses[buses[i].parent];
+            (parent.paused, parent.solo_path)
+        };
+        let bus = &mut buses[i];
+        bus.paused = paused || parent_paused;
+        bus.solo_path = soloed || parent_solo_path;
+        bus.solo_below = false;
+    }
+
+    // ...and a backward pass can pass state up it.
+    let mut any_soloed = false;
+    for i in (1..buses.len()).rev() {
+        let soloed = buses[i].shared.soloed.load(Ordering::Relaxed) || buses[i].solo_below;
+        any_soloed |= soloed;
+        let parent = buses[i].parent;
+        buses[parent].solo_below |= soloed;
+    }
+    any_soloed || buses[0].shared.soloed.load(Ordering::Relaxed)
+}
@@ -0,0 +1,89 @@
+use crate::queue::Queue;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// How often an EventListener's thread checks for new events
+const LISTENER_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Something that happened to a voice, reported by the Mixer through `MixerHandle::poll_event()` or an
+/// EventListener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VoiceEvent {
+    /// The voice it happened to, matching `VoiceHandle::id()`.
+    pub voice: u64,
+
+    /// The frame on the Mixer's clock that it happened at (see `MixerHandle::current_frame()`).
+    pub frame: u64,
+
+    /// What happened.
+    pub kind: VoiceEventKind,
+}
+
+/// The kinds of VoiceEvent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceEventKind {
+    /// The voice started playing.
+    Started,
+
+    /// The voice's Source ended, and the voice has been discarded.
+    Finished,
+
+    /// The voice was stopped, either with `VoiceHandle::stop()` or at its scheduled stop frame.
+    Stopped,
+
+    /// The voice was stolen to make room for another voice.
+    Stolen,
+
+    /// The voice was never played, because the Mixer's voice limits had been reached and nothing could be stolen.
+    Rejected,
+
+    /// The voice reached a marker added with `VoiceOptions::marker()`. This holds the marker's id.
+    Marker(u32),
+}
+
+/// A thread which passes the Mixer's voice events to a callback. Created with `MixerHandle::listen()`.
+///
+/// The callback is called on the listener's own thread, never the audio thread, so it's free to block or allocate.
+/// Dropping the EventListener stops the thread, waiting for it to finish if it's in the middle of a callback.
+pub struct EventListener {
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EventListener {
+    pub(super) fn new(events: Arc<Queue<VoiceEvent>>, mut callback: impl FnMut(VoiceEvent) + Send + 'static) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let running = running.clone();
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    while let Some(event) = events.pop() {
+                        callback(event);
+                    }
+                    thread::sleep(LISTENER_INTERVAL);
+                }
+            })
+        };
+        Self { running, thread: Some(thread) }
+    }
+}
+
+impl Drop for EventListener {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Reports an event from the audio thread. If nobody's reading events, they're simply dropped once the queue is full.
+pub(super) fn emit(events: &Queue<VoiceEvent>, voice: u64, frame: u64, kind: VoiceEventKind) {
+    let _ = events.push(VoiceEvent { voice, frame, kind });
+}
@@ -0,0 +1,70 @@
+use super::voice::Voice;
+use std::time::Duration;
+
+pub(super) const DEFAULT_MAX_VOICES: usize = 128;
+
+/// How a Mixer picks a voice to make room for a new one, when a voice limit has been reached.
+///
+/// A voice can only be stolen by a new voice of the same or higher priority. If there's no voice that can be
+/// stolen, the new voice is rejected instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StealPolicy {
+    /// Never steal; reject the new voice.
+    None,
+
+    /// Steal the voice which started playing the longest time ago.
+    Oldest,
+
+    /// Steal the voice with the lowest output level in the last block.
+    Quietest,
+
+    /// Steal the voice with the lowest priority, or the oldest of those if there's a tie.
+    #[default]
+    LowestPriority,
+}
+
+/// A limit on how many instances of a group of sounds can play at once, set with `MixerHandle::set_group_limit()`.
+/// Voices are put into a group with `VoiceOptions::group()`.
+#[derive(Clone, Copy, Debug)]
+pub struct GroupLimit {
+    /// The maximum number of voices in this group that can be playing at once.
+    pub max_instances: usize,
+
+    /// The minimum time between two voices in this group starting. Voices added sooner than this are rejected.
+    pub cooldown: Duration,
+
+    /// Which voice in the group to steal when a new one is added and `max_instances` has been reached.
+    pub policy: StealPolicy,
+}
+
+pub(super) struct Group {
+    pub id: u32,
+    pub max_instances: usize,
+    pub cooldown: u64,
+    pub policy: StealPolicy,
+    pub last_start: Option<u64>,
+}
+
+impl GroupLimit {
+    /// Creates a limit of `max_instances` voices with no cooldown, which steals the oldest voice in the group.
+    pub fn new(max_instances: usize) -> Self {
+        Self { max_instances, cooldown: Duration::from_secs(0), policy: StealPolicy::Oldest }
+    }
+}
+
+/// Chooses a voice to be stolen from `candidates` in favour of a new voice with the given priority.
+/// Returns its index in the Mixer's voice list, or None if no voice can be stolen.
+pub(super) fn choose_victim<'a>(
+    candidates: impl Iterator<Item = (usize, &'a Voice)>,
+    policy: StealPolicy,
+    priority: i32,
+) -> Option<usize> {
+    let candidates = candidates.filter(|(_, voice)| voice.is_active() && voice.priority <= priority);
+    match policy {
+        StealPolicy::None => None,
+        StealPolicy::Oldest => candidates.min_by_key(|(_, voice)| voice.sequence),
+        StealPolicy::Quietest => candidates.min_by(|(_, a), (_, b)| a.level.total_cmp(&b.level)),
+        StealPolicy::LowestPriority => candidates.min_by_key(|(_, voice)| (voice.priority, voice.sequence)),
+    }
+    .map(|(index, _)| index)
+}
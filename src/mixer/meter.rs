@@ -0,0 +1,140 @@
+use crate::Sample;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+const DEFAULT_WINDOW: Duration = Duration::from_millis(50);
+
+/// A level meter on a voice or bus, available from `VoiceHandle::meter()` or `BusHandle::meter()`.
+/// The master bus's meter measures the Mixer's final output.
+///
+/// The Mixer measures the peak and RMS level over a window of time (50 ms by default), and publishes them at the end
+/// of each window, so readings are always for the most recent complete window. Like the handles they come from,
+/// Meters are cheap to clone, can be sent between threads, and never block.
+///
+/// Voices are measured after their gain and pan, and buses after their gain, ducking and dynamics, so a meter shows
+/// exactly what's passed on to the parent bus.
+#[derive(Clone)]
+pub struct Meter(pub(super) Arc<MeterShared>);
+
+pub(super) struct MeterShared {
+    peak: AtomicU32,
+    rms: AtomicU32,
+    clips: AtomicU64,
+    window: AtomicU64,
+}
+
+// The Mixer's side of a meter, which accumulates a window's worth of samples at a time
+pub(super) struct MeterState {
+    shared: Arc<MeterShared>,
+    channels: usize,
+    sample_rate: u32,
+    window: u64,
+    peak: Sample,
+    sum: f64,
+    frames: u64,
+    clips: u64,
+}
+
+impl Meter {
+    /// Returns the highest absolute sample value in the last window, as a linear level.
+    pub fn peak(&self) -> f32 {
+        f32::from_bits(self.0.peak.load(Ordering::Relaxed))
+    }
+
+    /// Returns the root-mean-square level of the last window, across all channels, as a linear level.
+    pub fn rms(&self) -> f32 {
+        f32::from_bits(self.0.rms.load(Ordering::Relaxed))
+    }
+
+    /// Returns how many samples have gone over 1.0 (0 dBFS) since the meter was created or last reset.
+    pub fn clip_count(&self) -> u64 {
+        self.0.clips.load(Ordering::Relaxed)
+    }
+
+    /// Resets the clip count to 0.
+    pub fn reset_clip_count(&self) {
+        self.0.clips.store(0, Ordering::Relaxed);
+    }
+
+    /// Sets how long each measurement is taken over. Longer windows give steadier readings which are updated less
+    /// often. The new window takes effect from the Mixer's next window.
+    pub fn set_window(&self, window: Duration) {
+        self.0.window.store(window.as_nanos().min(u128::from(u64::MAX)) as u64, Ordering::Relaxed);
+    }
+
+    /// Returns how long each measurement is taken over.
+    pub fn window(&self) -> Duration {
+        Duration::from_nanos(self.0.window.load(Ordering::Relaxed))
+    }
+}
+
+impl MeterShared {
+    pub fn new() -> Self {
+        Self {
+            peak: AtomicU32::new(0.0f32.to_bits()),
+            rms: AtomicU32::new(0.0f32.to_bits()),
+            clips: AtomicU64::new(0),
+            window: AtomicU64::new(DEFAULT_WINDOW.as_nanos() as u64),
+        }
+    }
+}
+
+impl MeterState {
+    pub fn new(shared: Arc<MeterShared>, channels: usize, sample_rate: u32) -> Self {
+        let mut state = Self { shared, channels, sample_rate, window: 0, peak: 0.0, sum: 0.0, frames: 0, clips: 0 };
+        state.update_window();
+        state
+    }
+
+    /// Measures one sample of the current frame.
+    pub fn add(&mut self, sample: Sample) {
+        let level = sample.abs();
+        self.peak = self.peak.max(level);
+        self.sum += f64::from(sample) * f64::from(sample);
+        if level > 1.0 {
+            self.clips += 1;
+        }
+    }
+
+    /// Moves on to the next frame, publishing the readings if this was the last frame of a window.
+    pub fn next_frame(&mut self) {
+        self.skip(1);
+    }
+
+    /// Measures a block of interleaved samples.
+    pub fn process(&mut self, buffer: &[Sample]) {
+        for frame in buffer.chunks_exact(self.channels) {
+            frame.iter().for_each(|s| self.add(*s));
+            self.next_frame();
+        }
+    }
+
+    /// Counts some frames of silence.
+    pub fn skip(&mut self, frames: usize) {
+        self.frames += frames as u64;
+        if self.frames >= self.window {
+            let rms = (self.sum / (self.frames * self.channels as u64) as f64).sqrt() as Sample;
+            self.shared.peak.store(self.peak.to_bits(), Ordering::Relaxed);
+            self.shared.rms.store(rms.to_bits(), Ordering::Relaxed);
+            if self.clips > 0 {
+                self.shared.clips.fetch_add(self.clips, Ordering::Relaxed);
+            }
+            self.peak = 0.0;
+            self.sum = 0.0;
+            self.frames = 0;
+            self.clips = 0;
+            self.update_window();
+        }
+    }
+
+    // Picks up any change to the window length, which is only done between windows
+    fn update_window(&mut self) {
+        let nanos = u128::from(self.shared.window.load(Ordering::Relaxed));
+        self.window = ((nanos * u128::from(self.sample_rate) / 1_000_000_000) as u64).max(1);
+    }
+}
@@ -0,0 +1,677 @@
+use crate::{
+    Sample, Source, StreamClock,
+    clock::ClockShared,
+    dynamics::{DuckingSettings, Dynamics, Processor},
+    queue::Queue,
+    remix::{ChannelLayout, RemixMatrix, Speaker},
+};
+use std::{
+    fmt,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+mod bus;
+mod events;
+mod limits;
+mod meter;
+mod sidechain;
+mod voice;
+
+pub use bus::BusHandle;
+use bus::{Bus, BusShared};
+pub use events::{EventListener, VoiceEvent, VoiceEventKind};
+use limits::Group;
+pub use limits::{GroupLimit, StealPolicy};
+pub use meter::Meter;
+use sidechain::Ducker;
+pub use sidechain::Sidechain;
+use voice::Voice;
+pub use voice::{VoiceHandle, VoiceOptions, VoiceState};
+
+const INIT_CAPACITY: usize = 16;
+const MASTER_BUS_NAME: &str = "master";
+
+/// The size of the queues between a Mixer and its MixerHandle.
+const QUEUE_SIZE: usize = 1024;
+
+/// The largest block, in frames, that a Mixer can render without reallocating its working buffers.
+pub const MAX_BLOCK_FRAMES: usize = 4096;
+
+/// Enough room in a working buffer for any Source up to 7.1 surround.
+const MAX_SOURCE_CHANNELS: usize = 8;
+
+static NEXT_MIXER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A simple additive mixer. Construct with `Mixer::new()`. This will return a Mixer and a MixerHandle.
+/// The Mixer is a Source object, and intended to be attached (directly or indirectly) to an OutputStream
+/// or any other place where a Source is expected.
+/// The MixerHandle is kept and used for dynamically adding Sources to the Mixer.
+///
+/// Sources are played through a tree of buses, rooted at the master bus. See `BusHandle` for details.
+///
+/// Sources with a different channel count to the Mixer are remixed to fit, using the standard matrices
+/// from `RemixMatrix::between`, or a custom matrix given in `VoiceOptions`.
+///
+/// Sources can be scheduled to start and stop at an exact frame of the Mixer's output, even partway through a block.
+/// The Mixer counts every frame it renders, starting from 0, and `MixerHandle::current_frame()` reads that count.
+/// `MixerHandle::frame_at_instant()` converts a time into a frame on that count, using the clock of the stream
+/// playing the Mixer.
+///
+/// Whenever a voice starts, finishes, is stopped, stolen or rejected, or reaches one of its markers, the Mixer reports
+/// it as a VoiceEvent. These can be read with `MixerHandle::poll_event()`, or passed to a callback on another thread
+/// with `MixerHandle::listen()`.
+///
+/// Every voice and bus has a Meter, which measures its peak and RMS level and counts clipped samples.
+///
+/// The number of voices playing at once is limited (to 128 by default), and so can the number of voices in any
+/// group of sounds. When a new Source would go over a limit, an existing voice is stolen to make room for it
+/// according to a StealPolicy, or if none can be stolen, the new one is rejected.
+///
+/// The Mixer is designed to be run on a real-time audio thread. Everything it receives from its MixerHandle comes
+/// through a pre-allocated lock-free queue, and everything it's finished with (such as Sources that have ended)
+/// is sent back through another one, to be freed by `MixerHandle::collect_garbage()`. As long as blocks are no larger
+/// than MAX_BLOCK_FRAMES, `write_samples()` will never allocate, free or block.
+pub struct Mixer {
+    channels: usize,
+    voices: Vec<Voice>,
+    buses: Vec<Bus>,
+    groups: Vec<Group>,
+    max_voices: usize,
+    steal_policy: StealPolicy,
+    next_sequence: u64,
+    frame: u64,
+    clock: Arc<AtomicU64>,
+    timing: Arc<ClockShared>,
+    input_buffer: Vec<Sample>,
+    remix_buffer: Vec<Sample>,
+    sides: Box<[Sample]>,
+    commands: Arc<Queue<Command>>,
+    garbage: Arc<Queue<Garbage>>,
+    events: Arc<Queue<VoiceEvent>>,
+}
+
+/// Returned from Mixer::new(), and permanently associated with the Mixer created alongside it.
+/// Used for dynamically adding sounds to the Mixer with `handle.add()`, and for setting up its buses.
+pub struct MixerHandle {
+    id: usize,
+    channels: usize,
+    sample_rate: u32,
+    clock: Arc<AtomicU64>,
+    timing: Arc<ClockShared>,
+    commands: Arc<Queue<Command>>,
+    garbage: Arc<Queue<Garbage>>,
+    events: Arc<Queue<VoiceEvent>>,
+    state: Mutex<HandleState>,
+}
+
+// What the MixerHandle knows about the Mixer, so that it can allocate things in advance on its behalf
+struct HandleState {
+    buses: Vec<BusHandle>,
+    bus_capacity: usize,
+    groups: Vec<u32>,
+    group_capacity: usize,
+}
+
+/// Error type for Mixer calls
+#[derive(Debug, Clone, Copy)]
+pub enum Error {
+    /// Indicates that something could not be sent to the Mixer via a MixerHandle.
+    /// This usually happens because the Mixer no longer exists.
+    SendError,
+
+    /// The queue of things waiting for the Mixer to pick up is full. This means the Mixer isn't being run,
+    /// or an unusually large number of things were sent to it between two of its blocks.
+    QueueFull,
+
+    /// A RemixMatrix didn't match the channel counts of the Source and the Mixer it was meant for.
+    InvalidMatrix,
+
+    /// A BusHandle was used with a Mixer other than the one it was created for.
+    UnknownBus,
+
+    /// A bus was added with the same name as an existing bus in the Mixer.
+    DuplicateBusName,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::SendError => "the mixer no longer exists",
+            Self::QueueFull => "the mixer's command queue is full",
+            Self::InvalidMatrix => "the remix matrix doesn't match the source and mixer channel counts",
+            Self::UnknownBus => "the bus belongs to a different mixer",
+            Self::DuplicateBusName => "a bus with that name already exists",
+        })
+    }
+}
+
+impl std::error::Error for Error {}
+
+enum Command {
+    AddVoice(Voice),
+    AddBus(Bus),
+    SetMaxVoices(usize, Vec<Voice>),
+    SetDynamics(usize, Option<Processor>),
+    SetDucking(usize, Option<Ducker>),
+    SetStealPolicy(StealPolicy),
+    SetGroupLimit(Group),
+    ReserveBuses(Vec<Bus>),
+    ReserveGroups(Vec<Group>),
+}
+
+// Things the Mixer is finished with, sent back to the MixerHandle to be dropped off the audio thread.
+// Their contents are never read, only dropped.
+#[allow(dead_code)]
+enum Garbage {
+    Voice(Voice),
+    Voices(Vec<Voice>),
+    Buses(Vec<Bus>),
+    Groups(Vec<Group>),
+    Processor(Processor),
+    Ducker(Ducker),
+}
+
+impl Mixer {
+    /// Constructs a new Mixer and MixerHandle. `channels` is the number of channels wanted in the output data,
+    /// and `sample_rate` is the rate it will be played at, which is used for anything measured in time.
+    pub fn new(channels: usize, sample_rate: u32) -> (Self, MixerHandle) {
+        let commands = Arc::new(Queue::with_capacity(QUEUE_SIZE));
+        let garbage = Arc::new(Queue::with_capacity(QUEUE_SIZE));
+        let events = Arc::new(Queue::with_capacity(QUEUE_SIZE));
+        let clock = Arc::new(AtomicU64::new(0));
+        let timing = Arc::new(ClockShared::new(sample_rate));
+        let id = NEXT_MIXER_ID.fetch_add(1, Ordering::Relaxed);
+
+        // Which side of the listener each output channel is on, for panning
+        let sides = match ChannelLayout::from_channel_count(channels).speakers() {
+            Some(speakers) => speakers
+                .iter()
+                .map(|speaker| match speaker {
+                    Speaker::FrontLeft | Speaker::BackLeft | Speaker::SideLeft => -1.0,
+                    Speaker::FrontRight | Speaker::BackRight | Speaker::SideRight => 1.0,
+                    Speaker::Center | Speaker::Lfe => 0.0,
+                })
+                .collect(),
+            None => vec![0.0; channels].into_boxed_slice(),
+        };
+
+        let master = Arc::new(BusShared::new(id, 0, MASTER_BUS_NAME));
+        let mut buses = Vec::with_capacity(INIT_CAPACITY);
+        buses.push(Bus::new(master.clone(), 0, channels, sample_rate));
+
+        (
+            Self {
+                channels,
+                voices: Vec::with_capacity(limits::DEFAULT_MAX_VOICES * 2),
+                buses,
+                groups: Vec::with_capacity(INIT_CAPACITY),
+                max_voices: limits::DEFAULT_MAX_VOICES,
+                steal_policy: StealPolicy::default(),
+                next_sequence: 0,
+                frame: 0,
+                clock: clock.clone(),
+                timing: timing.clone(),
+                input_buffer: Vec::with_capacity(MAX_BLOCK_FRAMES * channels.max(MAX_SOURCE_CHANNELS)),
+                remix_buffer: Vec::with_capacity(MAX_BLOCK_FRAMES * channels),
+                sides,
+                commands: commands.clone(),
+                garbage: garbage.clone(),
+                events: events.clone(),
+            },
+            MixerHandle {
+                id,
+                channels,
+                sample_rate,
+                clock,
+                timing,
+                commands,
+                garbage,
+                events,
+                state: Mutex::new(HandleState {
+                    buses: vec![BusHandle(master)],
+                    bus_capacity: INIT_CAPACITY,
+                    groups: Vec::new(),
+                    group_capacity: INIT_CAPACITY,
+                }),
+            },
+        )
+    }
+
+    // Checks whether a new voice can be added, stealing an existing one if it would go over a limit
+    fn try_admit(&mut self, voice: &mut Voice) -> bool {
+        let frame = self.frame;
+        let (max_voices, steal_policy) = (self.max_voices, self.steal_policy);
+        let voices = &mut self.voices;
+        let groups = &mut self.groups;
+
+        // Check the voice's group limit first, if it has one
+        let mut group_victim = None;
+        let group = voice.group.and_then(|id| groups.iter_mut().find(|group| group.id == id));
+        if let Some(group) = &group {
+            if group.last_start.is_some_and(|start| frame - start < group.cooldown) {
+                return false
+            }
+            let in_group = voices.iter().enumerate().filter(|(_, v)| v.group == Some(group.id) && v.is_active());
+            if in_group.clone().count() >= group.max_instances {
+                match limits::choose_victim(in_group, group.policy, voice.priority) {
+                    Some(victim) => group_victim = Some(victim),
+                    None => return false,
+                }
+            }
+        }
+
+        // Then the global limit, counting any voice stolen from the group as already gone
+        let active = voices.iter().filter(|v| v.is_active()).count() - usize::from(group_victim.is_some());
+        let mut victim = None;
+        if active >= max_voices {
+            let candidates = voices.iter().enumerate().filter(|(i, _)| Some(*i) != group_victim);
+            match limits::choose_victim(candidates, steal_policy, voice.priority) {
+                Some(v) => victim = Some(v),
+                None => return false,
+            }
+        }
+
+        // Pushing past the capacity of the voice list would allocate, so it's treated as a hard limit
+        if voices.len() == voices.capacity() {
+            return false
+        }
+
+        for index in group_victim.into_iter().chain(victim) {
+            voices[index].stolen = true;
+        }
+        if let Some(group) = group {
+            group.last_start = Some(frame);
+        }
+        voice.sequence = self.next_sequence;
+        self.next_sequence += 1;
+        true
+    }
+
+    // Sends something back to the MixerHandle to be dropped. If the garbage queue is full, it has to be dropped here.
+    fn discard(&self, garbage: Garbage) {
+        let _ = self.garbage.push(garbage);
+    }
+
+    fn run_command(&mut self, command: Command) {
+        match command {
+            Command::AddVoice(mut voice) => {
+                if self.try_admit(&mut voice) {
+                    self.voices.push(voice);
+                } else {
+                    voice.shared.set_state(VoiceState::Rejected);
+                    events::emit(&self.events, voice.shared.id, self.frame, VoiceEventKind::Rejected);
+                    self.discard(Garbage::Voice(voice));
+                }
+            },
+            Command::AddBus(bus) => self.buses.push(bus),
+            Command::SetMaxVoices(max, mut voices) => {
+                self.max_voices = max;
+                if voices.capacity() >= self.voices.len() {
+                    voices.append(&mut self.voices);
+                    std::mem::swap(&mut voices, &mut self.voices);
+                }
+                self.discard(Garbage::Voices(voices));
+            },
+            Command::SetDynamics(bus, mut dynamics) => {
+                std::mem::swap(&mut dynamics, &mut self.buses[bus].dynamics);
+                if let Some(old) = dynamics {
+                    self.discard(Garbage::Processor(old));
+                }
+            },
+            Command::SetDucking(bus, mut ducker) => {
+                std::mem::swap(&mut ducker, &mut self.buses[bus].ducker);
+                if let Some(old) = ducker {
+                    self.discard(Garbage::Ducker(old));
+                }
+            },
+            Command::SetStealPolicy(policy) => self.steal_policy = policy,
+            Command::SetGroupLimit(group) => match self.groups.iter_mut().find(|g| g.id == group.id) {
+                Some(g) => *g = Group { last_start: g.last_start, ..group },
+                None => self.groups.push(group),
+            },
+            Command::ReserveBuses(mut buses) => {
+                buses.append(&mut self.buses);
+                std::mem::swap(&mut buses, &mut self.buses);
+                self.discard(Garbage::Buses(buses));
+            },
+            Command::ReserveGroups(mut groups) => {
+                groups.append(&mut self.groups);
+                std::mem::swap(&mut groups, &mut self.groups);
+                self.discard(Garbage::Groups(groups));
+            },
+        }
+    }
+}
+
+impl Source for Mixer {
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        buffer.iter_mut().for_each(|x| *x = 0.0);
+
+        // Note when this block started, so that times can be turned into frames
+        let end = self.frame + (buffer.len() / self.channels) as u64;
+        self.timing.publish(Duration::ZERO, self.frame, end, true);
+
+        // Check for new sources, buses and settings...
+        while let Some(command) = self.commands.pop() {
+            self.run_command(command);
+        }
+
+        let any_soloed = bus::update_flags(&mut self.buses);
+        for bus in self.buses.iter_mut() {
+            bus.buffer.clear();
+            bus.buffer.resize(buffer.len(), 0.0);
+        }
+
+        let buses = &mut self.buses;
+        let input_buffer = &mut self.input_buffer;
+        let remix_buffer = &mut self.remix_buffer;
+        let sides = &self.sides;
+        let frame = self.frame;
+        let events = &*self.events;
+        for voice in self.voices.iter_mut().filter(|voice| !voice.done) {
+            let bus = &mut buses[voice.bus];
+            voice.done =
+                !bus.is_frozen() && !voice.mix(&mut bus.buffer, frame, input_buffer, remix_buffer, sides, events);
+        }
+
+        // Send finished voices back to the handle, rather than dropping them here. If the garbage queue is full,
+        // they can wait in the voice list until there's room.
+        let mut i = 0;
+        while i < self.voices.len() {
+            if self.voices[i].done {
+                let voice = self.voices.swap_remove(i);
+                if let Err(Garbage::Voice(voice)) = self.garbage.push(Garbage::Voice(voice)) {
+                    self.voices.push(voice);
+                    break
+                }
+            } else {
+                i += 1;
+            }
+        }
+
+        // Children always come after their parents, so going backwards mixes each bus before its parent needs it
+        for i in (1..self.buses.len()).rev() {
+            let (parents, children) = self.buses.split_at_mut(i);
+            let bus = &mut children[0];
+            bus.mix_into(&mut parents[bus.parent].buffer, self.channels, any_soloed);
+        }
+        self.buses[0].mix_into(buffer, self.channels, any_soloed);
+
+        self.frame += (buffer.len() / self.channels) as u64;
+        self.clock.store(self.frame, Ordering::Relaxed);
+        buffer.len()
+    }
+
+    fn channel_count(&self) -> usize {
+        self.channels
+    }
+}
+
+impl MixerHandle {
+    /// Adds a Source to the master bus of the Mixer associated with this handle. The Mixer will play the Source
+    /// until it ends, then discard it. The returned VoiceHandle can be used to control the Source while it plays.
+    pub fn add(&self, source: impl Source + Send + Sync + 'static) -> Result<VoiceHandle, Error> {
+        self.add_with(source, VoiceOptions::new())
+    }
+
+    /// Adds a Source to the Mixer with the given options, such as which bus to play it on.
+    pub fn add_with(
+        &self,
+        source: impl Source + Send + Sync + 'static,
+        mut options: VoiceOptions,
+    ) -> Result<VoiceHandle, Error> {
+        if let Some((clock, time)) = options.start_time.take() {
+            options.start_frame = Some(self.frame_at_instant(&clock, time));
+        }
+        if let Some((clock, time)) = options.stop_time.take() {
+            options.stop_frame = Some(self.frame_at_instant(&clock, time));
+        }
+        let bus = match &options.bus {
+            Some(bus) => self.bus_index(bus)?,
+            None => 0,
+        };
+        let matrix = match options.matrix.clone() {
+            Some(matrix) => {
+                if matrix.input_count() != source.channel_count() || matrix.output_count() != self.channels {
+                    return Err(Error::InvalidMatrix)
+                }
+                matrix
+            },
+            None => {
+                let from = ChannelLayout::from_channel_count(source.channel_count());
+                let to = ChannelLayout::from_channel_count(self.channels);
+                RemixMatrix::between(from, to)
+            },
+        };
+
+        let voice = Voice::new(Box::new(source), matrix, &options, bus, self.sample_rate);
+        let handle = VoiceHandle(voice.shared.clone());
+        self.send(Command::AddVoice(voice))?;
+        Ok(handle)
+    }
+
+    /// Sets the maximum number of voices that can play at once. If there are already more than this, none will be
+    /// stopped, but new voices will have to steal from the old ones until the count is back under the limit.
+    pub fn set_max_voices(&self, max_voices: usize) -> Result<(), Error> {
+        // Stolen voices take a block to fade out, so there can briefly be up to twice as many voices as the limit
+        self.send(Command::SetMaxVoices(max_voices, Vec::with_capacity(max_voices * 2)))
+    }
+
+    /// Sets which voice will be stolen when a new one is added and the maximum voice count has been reached.
+    /// The default is `StealPolicy::LowestPriority`.
+    pub fn set_steal_policy(&self, policy: StealPolicy) -> Result<(), Error> {
+        self.send(Command::SetStealPolicy(policy))
+    }
+
+    /// Limits how many voices in the given group can play at once, and how often they can be started.
+    /// This replaces any previous limit on the same group.
+    pub fn set_group_limit(&self, group: u32, limit: GroupLimit) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if !state.groups.contains(&group) {
+            if state.groups.len() == state.group_capacity {
+                state.group_capacity *= 2;
+                self.send(Command::ReserveGroups(Vec::with_capacity(state.group_capacity)))?;
+            }
+            state.groups.push(group);
+        }
+
+        let cooldown = (limit.cooldown.as_secs_f64() * f64::from(self.sample_rate)).round() as u64;
+        self.send(Command::SetGroupLimit(Group {
+            id: group,
+            max_instances: limit.max_instances,
+            cooldown,
+            policy: limit.policy,
+            last_start: None,
+        }))
+    }
+
+    /// Returns the number of frames the Mixer has rendered so far, which is the frame its next block will start at.
+    /// This is the clock used for scheduling voices with `VoiceOptions::start_at()` and `VoiceHandle::stop_at()`.
+    pub fn current_frame(&self) -> u64 {
+        self.clock.load(Ordering::Relaxed)
+    }
+
+    /// Converts a time on the Mixer's clock, measured from the start of its first block, to a frame.
+    /// For example, `handle.frame_at(Duration::from_secs(2))` is the frame exactly two seconds into the output.
+    pub fn frame_at(&self, time: Duration) -> u64 {
+        (time.as_secs_f64() * f64::from(self.sample_rate)).round() as u64
+    }
+
+    /// Converts a frame on the Mixer's clock to the time since the start of its first block.
+    pub fn time_at(&self, frame: u64) -> Duration {
+        Duration::from_secs_f64(frame as f64 / f64::from(self.sample_rate))
+    }
+
+    /// Returns the frame on the Mixer's clock which will be heard at the given time, or was heard then, going by the
+    /// clock of the stream playing the Mixer. This turns game time into frames for scheduling voices, for example
+    /// `VoiceOptions::new().start_at(handle.frame_at_instant(&clock, Instant::now() + delay))`.
+    ///
+    /// The Mixer notes when it renders each block, and the StreamClock says how long it takes from then until it's
+    /// heard. Times after the Mixer's last block are worked out as though it's still being played, even if it isn't
+    /// (such as while the stream is paused), and every time before its first block gives 0.
+    pub fn frame_at_instant(&self, clock: &StreamClock, time: Instant) -> u64 {
+        let rendered = time.checked_sub(clock.latency()).unwrap_or(time);
+        self.timing.frame_at(rendered)
+    }
+
+    /// Returns the time at which the given frame on the Mixer's clock will be heard, or was heard, going by the clock
+    /// of the stream playing the Mixer. This is the reverse of `frame_at_instant()`.
+    pub fn instant_at_frame(&self, clock: &StreamClock, frame: u64) -> Instant {
+        self.timing.time_at(frame) + clock.latency()
+    }
+
+    /// Returns the master bus, which every other bus and voice is eventually mixed into.
+    pub fn master(&self) -> BusHandle {
+        self.state.lock().unwrap().buses[0].clone()
+    }
+
+    /// Adds a new, empty bus to the Mixer, as a child of `parent`. Its name must be unique within the Mixer.
+    pub fn add_bus(&self, name: &str, parent: &BusHandle) -> Result<BusHandle, Error> {
+        let parent = self.bus_index(parent)?;
+        let mut state = self.state.lock().unwrap();
+        if state.buses.iter().any(|bus| bus.name() == name) {
+            return Err(Error::DuplicateBusName)
+        }
+        if state.buses.len() == state.bus_capacity {
+            state.bus_capacity *= 2;
+            self.send(Command::ReserveBuses(Vec::with_capacity(state.bus_capacity)))?;
+        }
+
+        let shared = Arc::new(BusShared::new(self.id, state.buses.len(), name));
+        self.send(Command::AddBus(Bus::new(shared.clone(), parent, self.channels, self.sample_rate)))?;
+        let handle = BusHandle(shared);
+        state.buses.push(handle.clone());
+        Ok(handle)
+    }
+
+    /// Puts a limiter or soft clipper on a bus, replacing whatever was there before. Putting one on the master bus
+    /// will stop the Mixer's output from clipping, however many sounds are playing at once.
+    pub fn set_dynamics(&self, bus: &BusHandle, dynamics: Dynamics) -> Result<(), Error> {
+        let bus = self.bus_index(bus)?;
+        self.send(Command::SetDynamics(bus, Processor::new(dynamics, self.channels, self.sample_rate)))
+    }
+
+    /// Makes a bus duck (turn itself down) whenever the level of `key` goes over a threshold, such as to dip the
+    /// music while dialogue is playing. The key can be any bus or voice in this Mixer, and is measured after its own
+    /// gain. This replaces any ducking that was already set up on the bus.
+    pub fn set_ducking(
+        &self,
+        bus: &BusHandle,
+        key: impl Into<Sidechain>,
+        settings: DuckingSettings,
+    ) -> Result<(), Error> {
+        let key = key.into();
+        if let Sidechain::Bus(key) = &key {
+            self.bus_index(key)?;
+        }
+        let bus = self.bus_index(bus)?;
+        self.send(Command::SetDucking(bus, Some(Ducker::new(key, &settings, self.sample_rate))))
+    }
+
+    /// Removes any ducking from a bus.
+    pub fn remove_ducking(&self, bus: &BusHandle) -> Result<(), Error> {
+        let bus = self.bus_index(bus)?;
+        self.send(Command::SetDucking(bus, None))
+    }
+
+    /// Finds a bus in the Mixer by name. The master bus is called "master".
+    pub fn bus(&self, name: &str) -> Option<BusHandle> {
+        self.state.lock().unwrap().buses.iter().find(|bus| bus.name() == name).cloned()
+    }
+
+    fn bus_index(&self, bus: &BusHandle) -> Result<usize, Error> {
+        if bus.0.mixer == self.id { Ok(bus.0.index) } else { Err(Error::UnknownBus) }
+    }
+
+    /// Drops everything the Mixer has finished with, such as Sources which have ended. This is done automatically
+    /// whenever anything is sent to the Mixer, but should also be called regularly (eg. once per frame) if that
+    /// doesn't happen often, to free memory and to make sure the Mixer's return queue doesn't fill up.
+    pub fn collect_garbage(&self) {
+        while self.garbage.pop().is_some() {}
+    }
+
+    /// Returns the oldest voice event that hasn't been read yet, or None if there aren't any. This never blocks.
+    ///
+    /// Events are held in a fixed-size queue, and any that happen while it's full are lost, so this should be called
+    /// regularly (eg. once per frame) until it returns None.
+    pub fn poll_event(&self) -> Option<VoiceEvent> {
+        self.events.pop()
+    }
+
+    /// Starts a thread which calls `callback` with each voice event as it arrives, until the returned EventListener
+    /// is dropped. Events taken by the listener won't be seen by `poll_event()`, so only one of the two should be used.
+    pub fn listen(&self, callback: impl FnMut(VoiceEvent) + Send + 'static) -> EventListener {
+        EventListener::new(self.events.clone(), callback)
+    }
+
+    fn send(&self, command: Command) -> Result<(), Error> {
+        self.collect_garbage();
+        if Arc::strong_count(&self.commands) == 1 {
+            return Err(Error::SendError)
+        }
+        self.commands.push(command).ok().ok_or(Error::QueueFull)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mixer, VoiceOptions};
+    use crate::{Sample, Source, StreamClock, clock::ClockShared};
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    // Plays a constant value forever
+    pub(super) struct Constant(pub Sample);
+
+    impl Source for Constant {
+        fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+            buffer.iter_mut().for_each(|s| *s = self.0);
+            buffer.len()
+        }
+
+        fn channel_count(&self) -> usize {
+            1
+        }
+    }
+
+    #[test]
+    fn converts_between_instants_and_frames() {
+        let (mut mixer, handle) = Mixer::new(1, 48000);
+        let clock = StreamClock(Arc::new(ClockShared::new(48000)));
+        clock.0.publish(Duration::from_millis(10), 0, 480, true);
+
+        let before = Instant::now();
+        mixer.write_samples(&mut [0.0; 480]);
+
+        // The first block started just after `before`, and is heard 10 ms later, so 5 ms after that is its 240th frame
+        let frame = handle.frame_at_instant(&clock, before + Duration::from_millis(15));
+        assert!((200..=240).contains(&frame), "wrong frame: {}", frame);
+
+        let time = handle.instant_at_frame(&clock, 4800);
+        assert!(handle.frame_at_instant(&clock, time).abs_diff(4800) <= 1);
+        assert!(time >= before + Duration::from_millis(110));
+    }
+
+    #[test]
+    fn voice_starts_at_stream_time() {
+        let (mut mixer, handle) = Mixer::new(1, 48000);
+        let clock = StreamClock(Arc::new(ClockShared::new(48000)));
+        clock.0.publish(Duration::ZERO, 0, 480, true);
+        let mut buffer = vec![0.0; 480];
+        mixer.write_samples(&mut buffer);
+
+        // The first block began about now, so this is about halfway through the second
+        let time = Instant::now() + Duration::from_millis(15);
+        handle.add_with(Constant(1.0), VoiceOptions::new().start_at_time(&clock, time)).unwrap();
+        let first_block = mixer.write_samples(&mut buffer);
+        let start = buffer.iter().position(|s| *s != 0.0).unwrap();
+        assert_eq!(first_block, buffer.len());
+        assert!((220..=300).contains(&start), "started at the wrong frame: {}", start);
+    }
+}
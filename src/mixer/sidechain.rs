@@ -0,0 +1,132 @@
+use super::{BusHandle, VoiceHandle, bus::BusShared, voice::VoiceShared};
+use crate::{
+    Sample,
+    dynamics::{DuckingSettings, db_to_gain, gain_to_db},
+};
+use std::sync::Arc;
+
+/// The signal which drives a ducker, set up with `MixerHandle::set_ducking()`.
+#[derive(Clone)]
+pub enum Sidechain {
+    /// Duck whenever this bus is loud, such as a bus that all dialogue is routed to.
+    Bus(BusHandle),
+
+    /// Duck whenever this voice is loud.
+    Voice(VoiceHandle),
+}
+
+// Reduces the gain of a bus while its sidechain key is over a threshold
+pub(super) struct Ducker {
+    key: Key,
+    threshold_db: f32,
+    slope: f32,
+    attack: f32,
+    release: f32,
+    hold: u64,
+    hold_left: u64,
+
+    // Current gain reduction in dB, always 0 or above
+    reduction: f32,
+}
+
+enum Key {
+    Bus(Arc<BusShared>),
+    Voice(Arc<VoiceShared>),
+}
+
+impl From<BusHandle> for Sidechain {
+    fn from(bus: BusHandle) -> Self {
+        Self::Bus(bus)
+    }
+}
+
+impl From<VoiceHandle> for Sidechain {
+    fn from(voice: VoiceHandle) -> Self {
+        Self::Voice(voice)
+    }
+}
+
+impl Ducker {
+    pub fn new(key: Sidechain, settings: &DuckingSettings, sample_rate: u32) -> Self {
+        let rate = f64::from(sample_rate);
+        let coefficient = |time: std::time::Duration| {
+            let frames = (time.as_secs_f64() * rate).max(1.0);
+            (1.0 - (-1.0 / frames).exp()) as f32
+        };
+
+        Self {
+            key: match key {
+                Sidechain::Bus(bus) => Key::Bus(bus.0),
+                Sidechain::Voice(voice) => Key::Voice(voice.0),
+            },
+            threshold_db: settings.threshold_db,
+            slope: 1.0 - 1.0 / settings.ratio.max(1.0),
+            attack: coefficient(settings.attack),
+            release: coefficient(settings.release),
+            hold: (settings.hold.as_secs_f64() * rate).round() as u64,
+            hold_left: 0,
+            reduction: 0.0,
+        }
+    }
+
+    /// Ducks a block of interleaved samples in place, according to the key's level in its most recent block.
+    /// If the key is a bus that's mixed after this one, that will be the previous block.
+    pub fn process(&mut self, buffer: &mut [Sample], channels: usize) {
+        let level = match &self.key {
+            Key::Bus(bus) => bus.level(),
+            Key::Voice(voice) => voice.level(),
+        };
+        let over = gain_to_db(level) - self.threshold_db;
+        let target = if over > 0.0 { over * self.slope } else { 0.0 };
+
+        for frame in buffer.chunks_exact_mut(channels) {
+            if target >= self.reduction {
+                self.reduction += (target - self.reduction) * self.attack;
+                self.hold_left = self.hold;
+            } else if self.hold_left > 0 {
+                self.hold_left -= 1;
+            } else {
+                self.reduction += (target - self.reduction) * self.release;
+            }
+
+            let gain = db_to_gain(-self.reduction);
+            frame.iter_mut().for_each(|s| *s *= gain);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Mixer, VoiceOptions, tests::Constant};
+    use crate::{Source, dynamics::DuckingSettings};
+    use std::time::Duration;
+
+    #[test]
+    fn ducked_bus_recovers_after_key_voice_stops() {
+        let (mut mixer, handle) = Mixer::new(1, 48000);
+        let music = handle.add_bus("music", &handle.master()).unwrap();
+        handle.add_with(Constant(0.5), VoiceOptions::new().bus(&music)).unwrap();
+        let key = handle.add(Constant(0.25)).unwrap();
+        let settings = DuckingSettings {
+            threshold_db: -30.0,
+            ratio: 4.0,
+            attack: Duration::from_millis(1),
+            release: Duration::from_millis(10),
+            hold: Duration::ZERO,
+        };
+        handle.set_ducking(&music, key.clone(), settings).unwrap();
+
+        // 0.25 is about 18 dB over the threshold, so the music is turned down by about 13.5 dB
+        let mut buffer = vec![0.0; 4800];
+        mixer.write_samples(&mut buffer);
+        let ducked = buffer[buffer.len() - 1] - 0.25;
+        assert!(ducked < 0.15, "music wasn't ducked: {}", ducked);
+
+        key.stop();
+        for _ in 0..5 {
+            mixer.write_samples(&mut buffer);
+        }
+        let recovered = buffer[buffer.len() - 1];
+        assert!((recovered - 0.5).abs() < 0.01, "music is still ducked: {}", recovered);
+    }
+}
@@ -0,0 +1,475 @@
+use super::{
+    BusHandle,
+    events::{self, VoiceEvent, VoiceEventKind},
+    meter::{Meter, MeterShared, MeterState},
+};
+use crate::{Sample, Source, StreamClock, queue::Queue, remix::RemixMatrix};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+// How many frames a voice is faded out over before a scheduled stop, to avoid a click
+const STOP_FADE_FRAMES: usize = 64;
+
+static NEXT_VOICE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Returned when adding a Source to a Mixer, and used to control that Source while it plays.
+///
+/// VoiceHandles are cheap to clone and can be sent between threads. Every call is a single atomic operation,
+/// so nothing here will ever block the Mixer or be blocked by it. Changes take effect at the start of the
+/// Mixer's next block, and gain, pan, pause and stop changes are ramped over that block to avoid clicks.
+///
+/// Dropping every handle to a voice doesn't stop it: it will carry on playing until its Source ends.
+#[derive(Clone)]
+pub struct VoiceHandle(pub(super) Arc<VoiceShared>);
+
+/// Options for adding a Source to a Mixer with `MixerHandle::add_with()`.
+#[derive(Clone, Default)]
+pub struct VoiceOptions {
+    pub(super) bus: Option<BusHandle>,
+    pub(super) matrix: Option<RemixMatrix>,
+    pub(super) priority: i32,
+    pub(super) group: Option<u32>,
+    pub(super) start_frame: Option<u64>,
+    pub(super) stop_frame: Option<u64>,
+
+    // Times on a stream's clock to start and stop at instead, which are converted to frames when the voice is added
+    pub(super) start_time: Option<(StreamClock, Instant)>,
+    pub(super) stop_time: Option<(StreamClock, Instant)>,
+
+    pub(super) markers: Vec<(u64, u32)>,
+}
+
+/// The playback state of a voice, as last reported by the Mixer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceState {
+    /// The voice has been sent to the Mixer, but the Mixer hasn't picked it up yet, or it's scheduled to start later.
+    Queued,
+
+    /// The voice is currently playing.
+    Playing,
+
+    /// The voice has been paused and is waiting to be resumed.
+    Paused,
+
+    /// The voice was stopped with `VoiceHandle::stop()` and has been discarded.
+    Stopped,
+
+    /// The voice's Source ended and it has been discarded.
+    Finished,
+
+    /// The voice was stolen to make room for another voice, and has been discarded.
+    Stolen,
+
+    /// The voice was never played, because the Mixer's voice limits had been reached and nothing could be stolen.
+    Rejected,
+}
+
+pub(super) struct VoiceShared {
+    pub id: u64,
+    gain: AtomicU32,
+    pan: AtomicU32,
+    paused: AtomicBool,
+    stopped: AtomicBool,
+    stop_frame: AtomicU64,
+    state: AtomicU8,
+    meter: Arc<MeterShared>,
+
+    // Peak output level in the last block, for use as a sidechain key
+    level: AtomicU32,
+}
+
+pub(super) struct Voice {
+    pub source: Box<dyn Source + Send + Sync + 'static>,
+    pub matrix: RemixMatrix,
+    pub shared: Arc<VoiceShared>,
+    pub bus: usize,
+    pub priority: i32,
+    pub group: Option<u32>,
+    pub start_frame: u64,
+
+    // Markers as (source frame, id), sorted by frame, and the next one to be reached
+    markers: Box<[(u64, u32)]>,
+    next_marker: usize,
+
+    // How many frames have been read from the source so far
+    position: u64,
+
+    // The order in which voices were admitted to the Mixer, for finding the oldest
+    pub sequence: u64,
+
+    // Whether this voice has been chosen to be stolen, and will fade out in the next block
+    pub stolen: bool,
+
+    // Whether this voice has finished, and should be removed from the Mixer
+    pub done: bool,
+
+    // Peak output level in the last block, for finding the quietest
+    pub level: Sample,
+    meter: MeterState,
+
+    // Gain applied to each output channel at the end of the last block, and whether there's been a last block
+    pub gains: Box<[Sample]>,
+    pub started: bool,
+}
+
+impl VoiceHandle {
+    /// Returns a number which identifies this voice in VoiceEvents. It's unique for the life of the program.
+    pub fn id(&self) -> u64 {
+        self.0.id
+    }
+
+    /// Stops the voice. It will fade out over the Mixer's next block, then be discarded.
+    /// A stopped voice can't be restarted.
+    pub fn stop(&self) {
+        self.0.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Schedules the voice to stop at an exact frame on the Mixer's clock (see `MixerHandle::current_frame()`).
+    /// It will be faded out over a few frames just before then, and be silent from that frame onwards.
+    /// If the frame has already passed by the time the Mixer sees this, it's the same as calling `stop()`.
+    pub fn stop_at(&self, frame: u64) {
+        self.0.stop_frame.store(frame, Ordering::Relaxed);
+    }
+
+    /// Pauses the voice. Its Source won't be asked for any more samples until `resume()` is called.
+    pub fn pause(&self) {
+        self.0.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resumes the voice from where it was paused.
+    pub fn resume(&self) {
+        self.0.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Sets the voice's linear gain, where 1.0 is unchanged. Negative values are treated as 0.
+    pub fn set_gain(&self, gain: f32) {
+        self.0.gain.store(gain.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the voice's linear gain.
+    pub fn gain(&self) -> f32 {
+        f32::from_bits(self.0.gain.load(Ordering::Relaxed))
+    }
+
+    /// Sets the voice's stereo balance, from -1.0 (left only) to 1.0 (right only). 0.0 is centered.
+    ///
+    /// Panning attenuates the channels on the opposite side using a constant-power curve. It has no effect on
+    /// center channels, or on a Mixer with a mono or unknown channel layout.
+    pub fn set_pan(&self, pan: f32) {
+        self.0.pan.store(pan.clamp(-1.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    /// Returns the voice's stereo balance.
+    pub fn pan(&self) -> f32 {
+        f32::from_bits(self.0.pan.load(Ordering::Relaxed))
+    }
+
+    /// Returns the state of the voice, as of the end of the Mixer's last block.
+    pub fn state(&self) -> VoiceState {
+        self.0.state()
+    }
+
+    /// Returns true if the voice is queued or playing, ie. it hasn't been paused or discarded.
+    pub fn is_playing(&self) -> bool {
+        matches!(self.state(), VoiceState::Queued | VoiceState::Playing)
+    }
+
+    /// Returns the voice's level meter, which measures its output after its gain and pan.
+    pub fn meter(&self) -> Meter {
+        Meter(self.0.meter.clone())
+    }
+
+    /// Returns true if the voice has been discarded by the Mixer, whether it ended, was stopped, stolen or rejected.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.state(), VoiceState::Queued | VoiceState::Playing | VoiceState::Paused)
+    }
+}
+
+impl VoiceOptions {
+    /// Creates a set of default options: play on the master bus, with the standard remix matrix.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Plays the voice on the given bus instead of the master bus.
+    pub fn bus(mut self, bus: &BusHandle) -> Self {
+        self.bus = Some(bus.clone());
+        self
+    }
+
+    /// Remixes the voice's channels into the Mixer's channels using a custom matrix. The matrix must have as many
+    /// inputs as the Source has channels, and as many outputs as the Mixer.
+    pub fn matrix(mut self, matrix: RemixMatrix) -> Self {
+        self.matrix = Some(matrix);
+        self
+    }
+
+    /// Sets the voice's priority. When a voice limit is reached, a new voice can only steal a voice whose
+    /// priority is the same or lower. The default priority is 0.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Schedules the voice to start at an exact frame on the Mixer's clock (see `MixerHandle::current_frame()`),
+    /// even if that's partway through one of the Mixer's blocks. If the frame has already passed by the time the
+    /// Mixer sees the voice, it starts immediately. Until it starts, its state is `VoiceState::Queued`.
+    pub fn start_at(mut self, frame: u64) -> Self {
+        self.start_frame = Some(frame);
+        self.start_time = None;
+        self
+    }
+
+    /// Schedules the voice to stop at an exact frame on the Mixer's clock. See `VoiceHandle::stop_at()`.
+    pub fn stop_at(mut self, frame: u64) -> Self {
+        self.stop_frame = Some(frame);
+        self.stop_time = None;
+        self
+    }
+
+    /// Schedules the voice to start at the frame that will be heard at the given time, going by the clock of the
+    /// stream playing the Mixer. This is the same as `start_at()` with the frame from
+    /// `MixerHandle::frame_at_instant()`, worked out when the voice is added.
+    pub fn start_at_time(mut self, clock: &StreamClock, time: Instant) -> Self {
+        self.start_time = Some((clock.clone(), time));
+        self.start_frame = None;
+        self
+    }
+
+    /// Schedules the voice to stop at the frame that will be heard at the given time, going by the clock of the
+    /// stream playing the Mixer. See `start_at_time()`. To do the same with a voice that's already playing, pass
+    /// the frame from `MixerHandle::frame_at_instant()` to `VoiceHandle::stop_at()`.
+    pub fn stop_at_time(mut self, clock: &StreamClock, time: Instant) -> Self {
+        self.stop_time = Some((clock.clone(), time));
+        self.stop_frame = None;
+        self
+    }
+
+    /// Adds a marker at a position in the Source, measured in frames from its start. When the voice plays past it,
+    /// the Mixer will send a `VoiceEventKind::Marker` event with the given id, at the exact frame it was reached.
+    pub fn marker(mut self, position: u64, id: u32) -> Self {
+        self.markers.push((position, id));
+        self
+    }
+
+    /// Puts the voice into a group of sounds, such as all footsteps, which can be given its own instance limit
+    /// and cooldown with `MixerHandle::set_group_limit()`.
+    pub fn group(mut self, group: u32) -> Self {
+        self.group = Some(group);
+        self
+    }
+}
+
+impl VoiceShared {
+    pub fn new(options: &VoiceOptions) -> Self {
+        Self {
+            id: NEXT_VOICE_ID.fetch_add(1, Ordering::Relaxed),
+            gain: AtomicU32::new(1.0f32.to_bits()),
+            pan: AtomicU32::new(0.0f32.to_bits()),
+            paused: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            stop_frame: AtomicU64::new(options.stop_frame.unwrap_or(u64::MAX)),
+            state: AtomicU8::new(VoiceState::Queued as u8),
+            meter: Arc::new(MeterShared::new()),
+            level: AtomicU32::new(0.0f32.to_bits()),
+        }
+    }
+
+    pub fn state(&self) -> VoiceState {
+        match self.state.load(Ordering::Acquire) {
+            0 => VoiceState::Queued,
+            1 => VoiceState::Playing,
+            2 => VoiceState::Paused,
+            3 => VoiceState::Stopped,
+            4 => VoiceState::Finished,
+            5 => VoiceState::Stolen,
+            _ => VoiceState::Rejected,
+        }
+    }
+
+    pub fn set_state(&self, state: VoiceState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    pub fn level(&self) -> Sample {
+        f32::from_bits(self.level.load(Ordering::Relaxed))
+    }
+
+    fn set_level(&self, level: Sample) {
+        self.level.store(level.to_bits(), Ordering::Relaxed);
+    }
+}
+
+impl Voice {
+    pub fn new(
+        source: Box<dyn Source + Send + Sync + 'static>,
+        matrix: RemixMatrix,
+        options: &VoiceOptions,
+        bus: usize,
+        sample_rate: u32,
+    ) -> Self {
+        let channels = matrix.output_count();
+        let shared = Arc::new(VoiceShared::new(options));
+        let meter = MeterState::new(shared.meter.clone(), channels, sample_rate);
+        let mut markers = options.markers.clone().into_boxed_slice();
+        markers.sort_by_key(|(position, _)| *position);
+        Self {
+            source,
+            matrix,
+            shared,
+            bus,
+            priority: options.priority,
+            group: options.group,
+            start_frame: options.start_frame.unwrap_or(0),
+            markers,
+            next_marker: 0,
+            position: 0,
+            sequence: 0,
+            stolen: false,
+            done: false,
+            level: Sample::INFINITY,
+            meter,
+            gains: vec![0.0; channels].into_boxed_slice(),
+            started: false,
+        }
+    }
+
+    /// Returns true if this voice is still playing, and hasn't been chosen to be stolen.
+    pub fn is_active(&self) -> bool {
+        !self.stolen && !self.done
+    }
+
+    /// Renders the next block of this voice, adding it onto `output`, which starts at frame `block_start`.
+    /// `scratch` and `remixed` are working buffers, and `sides` gives the pan position of each output channel.
+    /// Anything that happens to the voice is reported to `events`. Returns false if the voice is done and should be
+    /// discarded.
+    pub fn mix(
+        &mut self,
+        output: &mut [Sample],
+        block_start: u64,
+        scratch: &mut Vec<Sample>,
+        remixed: &mut Vec<Sample>,
+        sides: &[Sample],
+        events: &Queue<VoiceEvent>,
+    ) -> bool {
+        let shared = &*self.shared;
+        let channels = sides.len();
+        let block_end = block_start + (output.len() / channels) as u64;
+
+        // A stop scheduled for a frame that's already gone is the same as an immediate stop
+        let stop_frame = shared.stop_frame.load(Ordering::Relaxed);
+        let stopping = shared.stopped.load(Ordering::Relaxed) || self.stolen || stop_frame <= block_start;
+        let pausing = shared.paused.load(Ordering::Relaxed);
+
+        // A voice that's already silent can be paused or stopped immediately
+        let silent = !self.started || self.gains.iter().all(|x| *x == 0.0);
+        if (stopping || pausing) && silent {
+            shared.set_level(0.0);
+            if stopping {
+                self.end(block_start, events);
+                return false
+            }
+            shared.set_state(VoiceState::Paused);
+            self.meter.skip(output.len() / channels);
+            return true
+        }
+
+        // Find which part of the block this voice should play in, if it starts or stops partway through
+        if self.start_frame >= block_end {
+            self.meter.skip(output.len() / channels);
+            return true
+        }
+        let offset = self.start_frame.saturating_sub(block_start) as usize;
+        let scheduled_stop = !stopping && stop_frame <= block_end;
+        let end =
+            if scheduled_stop { ((stop_frame - block_start) as usize).max(offset) } else { output.len() / channels };
+        let output = &mut output[(offset * channels)..(end * channels)];
+        let frames = end - offset;
+
+        let gain = if stopping || pausing { 0.0 } else { f32::from_bits(shared.gain.load(Ordering::Relaxed)) };
+        let pan = f32::from_bits(shared.pan.load(Ordering::Relaxed));
+        let target = |channel: usize| gain * pan_gain(pan, sides[channel]);
+
+        scratch.resize(frames * self.matrix.input_count(), 0.0);
+        let count = self.source.write_samples(scratch);
+        remixed.clear();
+        remixed.resize(frames * channels, 0.0);
+        self.matrix.mix_into(&scratch[..count], remixed);
+
+        if !self.started {
+            events::emit(events, shared.id, block_start + offset as u64, VoiceEventKind::Started);
+            self.gains.iter_mut().enumerate().for_each(|(channel, from)| *from = target(channel));
+            self.started = true;
+        }
+
+        // Ramp from last block's gains to this block's, and fade out quickly before a scheduled stop
+        let fade_start = if scheduled_stop { frames.saturating_sub(STOP_FADE_FRAMES) } else { frames };
+        let mut level: Sample = 0.0;
+        self.meter.skip(offset);
+        for (i, (in_frame, out_frame)) in
+            remixed.chunks_exact(channels).zip(output.chunks_exact_mut(channels)).enumerate()
+        {
+            let t = (i + 1) as Sample / frames as Sample;
+            let fade = if i < fade_start { 1.0 } else { (frames - i) as Sample / (frames - fade_start) as Sample };
+            for (channel, (in_sample, out_sample)) in in_frame.iter().zip(out_frame.iter_mut()).enumerate() {
+                let from = self.gains[channel];
+                let sample = in_sample * (from + (target(channel) - from) * t) * fade;
+                *out_sample += sample;
+                level = level.max(sample.abs());
+                self.meter.add(sample);
+            }
+            self.meter.next_frame();
+        }
+        self.gains.iter_mut().enumerate().for_each(|(channel, from)| *from = target(channel));
+        self.level = level;
+        shared.set_level(level);
+
+        // Report any markers that were passed in this block
+        let played = (count / self.matrix.input_count()) as u64;
+        let first_frame = block_start + offset as u64;
+        while let Some((position, id)) = self.markers.get(self.next_marker).copied() {
+            if position >= self.position + played {
+                break
+            }
+            let frame = first_frame + position.saturating_sub(self.position);
+            events::emit(events, shared.id, frame, VoiceEventKind::Marker(id));
+            self.next_marker += 1;
+        }
+        self.position += played;
+
+        if count < scratch.len() {
+            shared.set_level(0.0);
+            shared.set_state(VoiceState::Finished);
+            events::emit(events, shared.id, first_frame + played, VoiceEventKind::Finished);
+            false
+        } else if stopping || scheduled_stop {
+            self.end(first_frame + frames as u64, events);
+            false
+        } else {
+            shared.set_state(if pausing { VoiceState::Paused } else { VoiceState::Playing });
+            true
+        }
+    }
+
+    // Marks the voice as stopped or stolen, as of the given frame
+    fn end(&self, frame: u64, events: &Queue<VoiceEvent>) {
+        let (state, kind) = if self.stolen {
+            (VoiceState::Stolen, VoiceEventKind::Stolen)
+        } else {
+            (VoiceState::Stopped, VoiceEventKind::Stopped)
+        };
+        self.shared.set_level(0.0);
+        self.shared.set_state(state);
+        events::emit(events, self.shared.id, frame, kind);
+    }
+}
+
+// Balance gain for a channel at the given side (-1.0 left, 0.0 center, 1.0 right)
+fn pan_gain(pan: f32, side: f32) -> f32 {
+    if pan * side < 0.0 { (pan.abs() * std::f32::consts::FRAC_PI_2).cos().max(0.0) } else { 1.0 }
+}
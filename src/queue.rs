@@ -0,0 +1,233 @@
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A bounded, lock-free, multi-producer multi-consumer queue.
+///
+/// All memory is allocated up front, so pushing and popping never allocate, free or block, which makes this
+/// suitable for passing things to and from the audio thread. Each slot carries a sequence number which tells
+/// producers and consumers whether it's their turn to use it (this is Dmitry Vyukov's bounded MPMC design).
+pub(crate) struct Queue<T> {
+    slots: Box<[Slot<T>]>,
+    mask: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+struct Slot<T> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// SAFETY: a value is only ever accessed by the single thread which won the race for its slot,
+// so the queue is safe to share as long as the values themselves can be sent between threads.
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+    /// Creates a queue which can hold at least `capacity` items. The capacity is rounded up to a power of two.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(2).next_power_of_two();
+        let slots = (0..capacity)
+            .map(|i| Slot { sequence: AtomicUsize::new(i), value: UnsafeCell::new(MaybeUninit::uninit()) })
+            .collect();
+        Self { slots, mask: capacity - 1, head: AtomicUsize::new(0), tail: AtomicUsize::new(0) }
+    }
+
+    /// Adds an item to the back of the queue. If the queue is full, the item is given back.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence as isize - pos as isize;
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // SAFETY: winning the compare-exchange gives us exclusive access to this slot
+                        unsafe { (*slot.value.get()).write(value) };
+                        slot.sequence.store(pos + 1, Ordering::Release);
+                        return Ok(())
+                    },
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // The slot still holds a value from one lap ago, so the queue is full
+                return Err(value)
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Removes an item from the front of the queue, or returns None if it's empty.
+    pub fn pop(&self) -> Option<T> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence as isize - (pos + 1) as isize;
+            if diff == 0 {
+                match self.head.compare_exchange_weak(pos, pos + 1, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // SAFETY: winning the compare-exchange gives us exclusive access to this slot,
+                        // and its sequence number tells us a value was written to it
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        slot.sequence.store(pos + self.mask + 1, Ordering::Release);
+                        return Some(value)
+                    },
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                return None
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Queue;
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        thread,
+    };
+
+    // Counts how many times it's been dropped
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn pops_in_push_order() {
+        let queue = Queue::with_capacity(8);
+        for i in 0..5 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!((0..5).map(|_| queue.pop().unwrap()).collect::<Vec<_>>(), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn empty_queue_pops_nothing() {
+        let queue = Queue::<u32>::with_capacity(4);
+        assert_eq!(queue.pop(), None);
+        queue.push(1).unwrap();
+        assert_eq!(queue.pop(), Some(1));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn full_queue_gives_items_back() {
+        // Rounded up to 4
+        let queue = Queue::with_capacity(3);
+        for i in 0..4 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(queue.push(4), Err(4));
+        assert_eq!(queue.pop(), Some(0));
+        queue.push(4).unwrap();
+        assert_eq!(queue.push(5), Err(5));
+    }
+
+    #[test]
+    fn wraps_around() {
+        // Pushing three at a time into four slots moves the positions round the slots by a different amount each time
+        let queue = Queue::with_capacity(4);
+        let (mut pushed, mut popped) = (0, 0);
+        for _ in 0..100 {
+            for _ in 0..3 {
+                queue.push(pushed).unwrap();
+                pushed += 1;
+            }
+            for _ in 0..3 {
+                assert_eq!(queue.pop(), Some(popped));
+                popped += 1;
+            }
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn drops_items_left_behind() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let queue = Queue::with_capacity(8);
+        for _ in 0..5 {
+            assert!(queue.push(Counted(drops.clone())).is_ok());
+        }
+        drop(queue.pop());
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        drop(queue);
+        assert_eq!(drops.load(Ordering::Relaxed), 5);
+    }
+
+    #[test]
+    fn many_threads_pass_every_item_once() {
+        const PRODUCERS: usize = 4;
+        const CONSUMERS: usize = 4;
+        const ITEMS: usize = if cfg!(miri) { 100 } else { 20000 };
+
+        let queue = Arc::new(Queue::with_capacity(16));
+        let popped = Arc::new(AtomicUsize::new(0));
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|p| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..ITEMS {
+                        let mut value = p * ITEMS + i;
+                        while let Err(rejected) = queue.push(value) {
+                            value = rejected;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        let consumers: Vec<_> = (0..CONSUMERS)
+            .map(|_| {
+                let (queue, popped) = (queue.clone(), popped.clone());
+                thread::spawn(move || {
+                    // Items from each producer must come out in the order that producer pushed them
+                    let mut seen = Vec::new();
+                    let mut last = [None; PRODUCERS];
+                    while popped.load(Ordering::Relaxed) < PRODUCERS * ITEMS {
+                        match queue.pop() {
+                            Some(value) => {
+                                let producer = value / ITEMS;
+                                if let Some(last) = last[producer] {
+                                    assert!(last < value);
+                                }
+                                last[producer] = Some(value);
+                                seen.push(value);
+                                popped.fetch_add(1, Ordering::Relaxed);
+                            },
+                            None => thread::yield_now(),
+                        }
+                    }
+                    seen
+                })
+            })
+            .collect();
+
+        producers.into_iter().for_each(|producer| producer.join().unwrap());
+        let mut seen: Vec<_> = consumers.into_iter().flat_map(|consumer| consumer.join().unwrap()).collect();
+        seen.sort_unstable();
+        assert!(seen.into_iter().eq(0..PRODUCERS * ITEMS));
+    }
+}
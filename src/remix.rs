@@ -0,0 +1,229 @@
+use crate::Sample;
+
+const MINUS_3DB: Sample = std::f32::consts::FRAC_1_SQRT_2;
+
+/// A known speaker arrangement. Channel orders follow the WAVE_FORMAT_EXTENSIBLE convention used by most devices.
+///
+/// - Mono: C
+/// - Stereo: FL FR
+/// - Quad: FL FR BL BR
+/// - Surround51: FL FR C LFE SL SR
+/// - Surround71: FL FR C LFE BL BR SL SR
+///
+/// Any other channel count is `Unknown`, and is mixed with a simple fallback rule (see `RemixMatrix::between`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+    Quad,
+    Surround51,
+    Surround71,
+    Unknown(usize),
+}
+
+/// A single speaker position within a ChannelLayout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speaker {
+    FrontLeft,
+    FrontRight,
+    Center,
+    Lfe,
+    BackLeft,
+    BackRight,
+    SideLeft,
+    SideRight,
+}
+
+/// A matrix of gains describing how each input channel contributes to each output channel.
+///
+/// Gains are stored row-major by output channel, so `gains[output * input_count + input]` is the gain applied to
+/// `input` when it's added to `output`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemixMatrix {
+    inputs: usize,
+    outputs: usize,
+    gains: Box<[Sample]>,
+    identity: bool,
+}
+
+impl ChannelLayout {
+    /// Guesses the layout of a Source or output stream from its channel count alone.
+    pub fn from_channel_count(channels: usize) -> Self {
+        match channels {
+            1 => Self::Mono,
+            2 => Self::Stereo,
+            4 => Self::Quad,
+            6 => Self::Surround51,
+            8 => Self::Surround71,
+            n => Self::Unknown(n),
+        }
+    }
+
+    /// Returns the number of channels in this layout.
+    pub fn channel_count(&self) -> usize {
+        match self {
+            Self::Mono => 1,
+            Self::Stereo => 2,
+            Self::Quad => 4,
+            Self::Surround51 => 6,
+            Self::Surround71 => 8,
+            Self::Unknown(n) => *n,
+        }
+    }
+
+    /// Returns the speaker positions of this layout in channel order, or None if the layout is unknown.
+    pub fn speakers(&self) -> Option<&'static [Speaker]> {
+        use Speaker::*;
+        match self {
+            Self::Mono => Some(&[Center]),
+            Self::Stereo => Some(&[FrontLeft, FrontRight]),
+            Self::Quad => Some(&[FrontLeft, FrontRight, BackLeft, BackRight]),
+            Self::Surround51 => Some(&[FrontLeft, FrontRight, Center, Lfe, SideLeft, SideRight]),
+            Self::Surround71 => Some(&[FrontLeft, FrontRight, Center, Lfe, BackLeft, BackRight, SideLeft, SideRight]),
+            Self::Unknown(_) => None,
+        }
+    }
+}
+
+impl RemixMatrix {
+    /// Creates a matrix which maps `inputs` channels to `outputs` channels, with every gain set to 0.
+    pub fn new(inputs: usize, outputs: usize) -> Self {
+        assert!(inputs != 0);
+        assert!(outputs != 0);
+        Self { inputs, outputs, gains: vec![0.0; inputs * outputs].into_boxed_slice(), identity: false }
+    }
+
+    /// Creates a matrix which passes every channel straight through.
+    pub fn identity(channels: usize) -> Self {
+        let mut matrix = Self::new(channels, channels);
+        for channel in 0..channels {
+            matrix.gains[channel * channels + channel] = 1.0;
+        }
+        matrix.identity = true;
+        matrix
+    }
+
+    /// Creates a matrix from a row-major list of gains, one row per output channel.
+    /// Returns None if `gains` doesn't contain exactly `inputs * outputs` values.
+    pub fn from_gains(inputs: usize, outputs: usize, gains: impl Into<Box<[Sample]>>) -> Option<Self> {
+        let gains = gains.into();
+        if inputs == 0 || outputs == 0 || gains.len() != inputs * outputs {
+            return None
+        }
+        let mut matrix = Self { inputs, outputs, gains, identity: false };
+        matrix.identity = matrix.is_identity();
+        Some(matrix)
+    }
+
+    /// Creates the standard matrix for converting between two channel layouts.
+    ///
+    /// Known layouts are converted with the ITU-R BS.775 down-mix coefficients: speakers which don't exist in
+    /// the output are folded into their nearest neighbours at -3 dB, and LFE is discarded when down-mixing.
+    /// Up-mixing is passive, so a mono source is sent to the center speaker, or to front left and right at -3 dB
+    /// if there isn't one, and no surround channels are synthesized.
+    ///
+    /// If either layout is unknown, channels are mapped by index. Surplus input channels wrap around onto the
+    /// output channels, and surplus output channels are left silent, except that a mono input is sent to all of them.
+    pub fn between(from: ChannelLayout, to: ChannelLayout) -> Self {
+        let inputs = from.channel_count();
+        let outputs = to.channel_count();
+        if from == to {
+            return Self::identity(inputs)
+        }
+
+        let mut matrix = Self::new(inputs, outputs);
+        match (from.speakers(), to.speakers()) {
+            (Some(in_speakers), Some(out_speakers)) => {
+                for (input, speaker) in in_speakers.iter().copied().enumerate() {
+                    route(speaker, 1.0, out_speakers, &mut |output, gain| {
+                        matrix.gains[output * inputs + input] += gain;
+                    });
+                }
+            },
+            _ if inputs == 1 => matrix.gains.iter_mut().for_each(|g| *g = 1.0),
+            _ => {
+                for input in 0..inputs {
+                    matrix.gains[(input % outputs) * inputs + input] = 1.0;
+                }
+            },
+        }
+        matrix.identity = matrix.is_identity();
+        matrix
+    }
+
+    /// Returns the number of input channels this matrix expects.
+    pub fn input_count(&self) -> usize {
+        self.inputs
+    }
+
+    /// Returns the number of output channels this matrix produces.
+    pub fn output_count(&self) -> usize {
+        self.outputs
+    }
+
+    /// Returns the gain applied to `input` when it's added to `output`.
+    pub fn gain(&self, input: usize, output: usize) -> Sample {
+        self.gains[output * self.inputs + input]
+    }
+
+    /// Sets the gain applied to `input` when it's added to `output`.
+    pub fn set_gain(&mut self, input: usize, output: usize, gain: Sample) {
+        self.gains[output * self.inputs + input] = gain;
+        self.identity = self.is_identity();
+    }
+
+    /// Remixes interleaved `input` frames and adds the result onto interleaved `output` frames.
+    /// Stops at whichever of the two runs out of whole frames first.
+    pub fn mix_into(&self, input: &[Sample], output: &mut [Sample]) {
+        if self.identity {
+            for (in_sample, out_sample) in input.iter().copied().zip(output.iter_mut()) {
+                *out_sample += in_sample;
+            }
+        } else if self.inputs == 1 {
+            for (in_sample, out_frame) in input.iter().copied().zip(output.chunks_exact_mut(self.outputs)) {
+                for (out_sample, gain) in out_frame.iter_mut().zip(self.gains.iter()) {
+                    *out_sample += in_sample * gain;
+                }
+            }
+        } else {
+            for (in_frame, out_frame) in input.chunks_exact(self.inputs).zip(output.chunks_exact_mut(self.outputs)) {
+                for (out_sample, gains) in out_frame.iter_mut().zip(self.gains.chunks_exact(self.inputs)) {
+                    *out_sample += in_frame.iter().zip(gains.iter()).map(|(s, g)| s * g).sum::<Sample>();
+                }
+            }
+        }
+    }
+
+    fn is_identity(&self) -> bool {
+        self.inputs == self.outputs
+            && self.gains.iter().enumerate().all(|(i, g)| {
+                let (output, input) = (i / self.inputs, i % self.inputs);
+                *g == if input == output { 1.0 } else { 0.0 }
+            })
+    }
+}
+
+// Sends a speaker's signal to the output speakers it should end up in, following fallbacks for missing speakers.
+fn route(speaker: Speaker, gain: Sample, outputs: &[Speaker], f: &mut impl FnMut(usize, Sample)) {
+    use Speaker::*;
+
+    if let Some(index) = outputs.iter().position(|s| *s == speaker) {
+        return f(index, gain)
+    }
+    let has = |s: Speaker| outputs.contains(&s);
+
+    match speaker {
+        Center => {
+            route(FrontLeft, gain * MINUS_3DB, outputs, f);
+            route(FrontRight, gain * MINUS_3DB, outputs, f);
+        },
+        FrontLeft | FrontRight => route(Center, gain * MINUS_3DB, outputs, f),
+        Lfe => (),
+        BackLeft if has(SideLeft) => route(SideLeft, gain, outputs, f),
+        BackRight if has(SideRight) => route(SideRight, gain, outputs, f),
+        SideLeft if has(BackLeft) => route(BackLeft, gain, outputs, f),
+        SideRight if has(BackRight) => route(BackRight, gain, outputs, f),
+        BackLeft | SideLeft => route(FrontLeft, gain * MINUS_3DB, outputs, f),
+        BackRight | SideRight => route(FrontRight, gain * MINUS_3DB, outputs, f),
+    }
+}
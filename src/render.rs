@@ -0,0 +1,155 @@
+use crate::{
+    Sample, Source, StreamConfig, StreamEvent,
+    stream::{Renderer, StreamShared},
+};
+use std::sync::Arc;
+
+// How many frames are pulled from the Source at a time, if the config doesn't give a buffer size
+const DEFAULT_BLOCK_SIZE: u32 = 512;
+
+/// Renders a Source without an audio device, as fast as it can be computed. This is useful for tests, for baking
+/// sounds ahead of time, or for exporting audio to a file.
+///
+/// The OfflineRenderer takes a StreamConfig standing in for a device's. The Source is pulled in blocks of the config's
+/// buffer size (512 frames if it's None), and is resampled and remixed to the config's sample rate and channel count
+/// if it doesn't match, all by the same code an OutputStream uses, so the output is exactly what a device with that
+/// config would have been sent. Like OutputStream, a Source which panics is caught, reported as a
+/// `StreamEvent::SourcePanicked`, and replaced with silence.
+///
+/// The output is always made of Samples; `render_wav()` converts it to other formats the same way an OutputStream
+/// does for devices, with the config's dither. The config's sample format isn't used.
+pub struct OfflineRenderer<S>
+where
+    S: Source,
+{
+    shared: Arc<StreamShared>,
+    renderer: Renderer<S>,
+    config: StreamConfig,
+    block_size: usize,
+    frames: u64,
+    finished: bool,
+
+    /// The sample rate the Source was set up for.
+    pub sample_rate: u32,
+
+    /// The channel count the Source was set up for.
+    pub channel_count: u16,
+}
+
+impl<S> OfflineRenderer<S>
+where
+    S: Source,
+{
+    /// Creates an OfflineRenderer with the given config. Takes a closure which returns a Source, like
+    /// `OutputStreamBuilder::build()`. The params to the closure are the config's channel count and sample rate.
+    pub fn new<F>(config: StreamConfig, setup: F) -> Self
+    where
+        F: FnOnce(u16, u32) -> S,
+    {
+        let source = setup(config.channels, config.sample_rate);
+        Self::from_source(source, config.sample_rate, config)
+    }
+
+    /// Creates an OfflineRenderer for a Source which already exists, and plays at the given sample rate. It's resampled
+    /// and remixed to fit the config if it needs to be.
+    pub fn from_source(source: S, sample_rate: u32, config: StreamConfig) -> Self {
+        let channel_count = source.channel_count() as u16;
+        let shared = Arc::new(StreamShared::new(config, sample_rate));
+        let renderer = Renderer::new(source, channel_count, sample_rate, config, shared.clone());
+        let block_size = config.buffer_size.unwrap_or(DEFAULT_BLOCK_SIZE).max(1) as usize;
+        Self { shared, renderer, config, block_size, frames: 0, finished: false, sample_rate, channel_count }
+    }
+
+    /// Returns the config the output is rendered in.
+    pub fn config(&self) -> StreamConfig {
+        self.config
+    }
+
+    /// Returns how many frames have been rendered so far.
+    pub fn frames_rendered(&self) -> u64 {
+        self.frames
+    }
+
+    /// Returns true once the Source has ended. Anything rendered after that is silent.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns the oldest event that hasn't been read yet, or None if there aren't any. Only
+    /// `StreamEvent::SourcePanicked` is ever reported offline.
+    pub fn poll_event(&self) -> Option<StreamEvent> {
+        self.shared.events.pop()
+    }
+
+    /// Calls `f` with the Source, such as to change its settings between renders, and returns whatever `f` returns.
+    pub fn with_source<R>(&mut self, f: impl FnOnce(&mut S) -> R) -> R {
+        f(self.renderer.source_mut())
+    }
+
+    /// Consumes the OfflineRenderer, and returns its Source.
+    pub fn into_source(self) -> S {
+        self.renderer.into_source()
+    }
+
+    /// Fills the buffer with interleaved samples in the config's channel count, and returns how many of them came from
+    /// the Source. If that's fewer than the length of the buffer, the Source has ended and the rest is silent.
+    ///
+    /// The buffer's length should be a multiple of the channel count.
+    pub fn render_into(&mut self, buffer: &mut [Sample]) -> usize {
+        let channels = usize::from(self.config.channels);
+        let mut written = 0;
+        for block in buffer.chunks_mut(self.block_size * channels) {
+            let count = self.renderer.render(block);
+            if !self.finished {
+                written += count;
+                self.finished = count < block.len();
+            }
+        }
+        self.frames += (buffer.len() / channels) as u64;
+        written
+    }
+
+    /// Renders the given number of frames. If the Source ends, the rest is silent.
+    pub fn render(&mut self, frames: usize) -> Vec<Sample> {
+        let mut samples = vec![0.0; frames * usize::from(self.config.channels)];
+        self.render_into(&mut samples);
+        samples
+    }
+
+    /// Renders until the Source ends, or until `max_frames` frames have been rendered, whichever comes first.
+    /// Nothing after the end of the Source is included.
+    ///
+    /// Sources such as a Mixer never end by themselves, so it's important to give a sensible limit.
+    pub fn render_to_end(&mut self, max_frames: usize) -> Vec<Sample> {
+        let channels = usize::from(self.config.channels);
+        let mut samples = Vec::new();
+        let mut remaining = max_frames;
+        while remaining > 0 && !self.finished {
+            let frames = remaining.min(self.block_size);
+            let start = samples.len();
+            samples.resize(start + frames * channels, 0.0);
+            let written = self.render_into(&mut samples[start..]);
+
+            // The silence after the end isn't returned, so don't count it either
+            let end = written / channels;
+            self.frames -= (frames - end) as u64;
+            samples.truncate(start + end * channels);
+            remaining -= frames;
+        }
+        samples
+    }
+
+    /// Renders the given number of frames, and writes them to `writer` as a .wav file in the given format, with the
+    /// config's dither. See `wav::write()`.
+    #[cfg(feature = "wav")]
+    pub fn render_wav(
+        &mut self,
+        frames: usize,
+        format: crate::wav::Format,
+        writer: impl std::io::Write,
+    ) -> std::io::Result<()> {
+        let samples = self.render(frames);
+        let config = self.config;
+        crate::wav::write(writer, &samples, config.channels, config.sample_rate, format, config.dither)
+    }
+}
@@ -1,7 +1,14 @@
 use crate::{Sample, Source};
 
+mod variable;
+
+pub use variable::{MAX_RATE, RateHandle, VariableResampler};
+
 /// Implementation of a PQF resampler. Construct with: Resampler::new(source, source_rate, dest_rate)
 /// Once constructed, it will behave as a Source object which outputs samples at the target sample rate.
+///
+/// The ratio between the rates is fixed. To change it while playing, for pitch or Doppler effects, use a
+/// VariableResampler instead.
 pub struct Resampler<S>
 where
     S: Source,
@@ -31,7 +38,15 @@ where
 }
 
 impl<S: Source> Resampler<S> {
-    pub fn new(mut source: S, source_rate: u32, dest_rate: u32) -> Self {
+    pub fn new(source: S, source_rate: u32, dest_rate: u32) -> Self {
+        let mut resampler = Self::unprimed(source, source_rate, dest_rate);
+        resampler.prime();
+        resampler
+    }
+
+    // Sets up the Resampler without reading anything from the Source yet, as if it started with silence. Call
+    // `prime()` to read the first of it, as `new()` does.
+    pub(crate) fn unprimed(source: S, source_rate: u32, dest_rate: u32) -> Self {
         assert!(source_rate != 0);
         assert!(dest_rate != 0);
 
@@ -41,49 +56,6 @@ impl<S: Source> Resampler<S> {
         }
 
         fn sinc_filter(left: u32, gain: f64, cutoff: f64, i: u32) -> f64 {
-            #[inline]
-            fn sinc(x: f64) -> f64 {
-                if x == 0.0 {
-                    1.0
-                } else {
-                    let x_pi = x * std::f64::consts::PI;
-                    x_pi.sin() / x_pi
-                }
-            }
-
-            #[inline]
-            fn bessel_i0(x: f64) -> f64 {
-                // Just trust me on this one
-                let ax = x.abs();
-                if ax < 3.75 {
-                    let y = (x / 3.75).powi(2);
-                    1.0 + y
-                        * (3.5156229
-                            + y * (3.0899424 + y * (1.2067492 + y * (0.2659732 + y * (0.0360768 + y * 0.0045813)))))
-                } else {
-                    let y = 3.75 / ax;
-                    (ax.exp() / ax.sqrt())
-                        * (0.39894228
-                            + y * (0.01328592
-                                + y * (0.00225319
-                                    + y * (-0.00157565
-                                        + y * (0.00916281
-                                            + y * (-0.02057706
-                                                + y * (0.02635537 + y * (-0.01647633 + y * 0.00392377))))))))
-                }
-            }
-
-            #[inline]
-            fn kaiser(k: f64) -> f64 {
-                if !(-1.0..=1.0).contains(&k) {
-                    0.0
-                } else {
-                    // 6.20426 is the Kaiser beta value for a rejection of 65 dB.
-                    // The magic number at the end is bessel_i0(6.20426)
-                    bessel_i0(6.20426 * (1.0 - k.powi(2)).sqrt()) / 81.0332923199
-                }
-            }
-
             let left = f64::from(left);
             let x = f64::from(i) - left;
             kaiser(x / left) * 2.0 * gain * cutoff * sinc(2.0 * cutoff * x)
@@ -113,23 +85,6 @@ impl<S: Source> Resampler<S> {
             .into_boxed_slice();
 
         let filter_samples = ((kaiser_value_count + to as usize) / to as usize) * source.channel_count();
-        let mut filter_1 = Vec::with_capacity(filter_samples);
-        let mut filter_2 = Vec::with_capacity(filter_samples);
-
-        unsafe {
-            filter_1.set_len(filter_samples);
-            filter_2.set_len(filter_samples);
-        }
-
-        let last_sample = {
-            let len = source.write_samples(&mut filter_1);
-            if len == filter_samples {
-                let len = source.write_samples(&mut filter_2);
-                if len == filter_samples { None } else { Some(len) }
-            } else {
-                Some(len)
-            }
-        };
 
         Self {
             source,
@@ -137,15 +92,44 @@ impl<S: Source> Resampler<S> {
             to,
             left_offset,
             kaiser_values,
-            filter_1: filter_1.into_boxed_slice(),
-            filter_2: filter_2.into_boxed_slice(),
+            filter_1: vec![0.0; filter_samples].into_boxed_slice(),
+            filter_2: vec![0.0; filter_samples].into_boxed_slice(),
             whole_filter_size: filter_samples * 2,
             buffer_size: filter_samples,
             input_offset: 0,
             output_count: 0,
-            last_sample,
+            last_sample: None,
         }
     }
+
+    // Fills the filter from the Source
+    pub(crate) fn prime(&mut self) {
+        let filter_samples = self.buffer_size;
+        self.last_sample = {
+            let len = self.source.write_samples(&mut self.filter_1);
+            if len == filter_samples {
+                let len = self.source.write_samples(&mut self.filter_2);
+                if len == filter_samples { None } else { Some(len) }
+            } else {
+                Some(len)
+            }
+        };
+    }
+
+    /// Returns a reference to the Source being resampled.
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// Returns a mutable reference to the Source being resampled.
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// Consumes the Resampler, and returns the Source it was resampling.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
 }
 
 impl<S: Source> Source for Resampler<S> {
@@ -245,3 +229,43 @@ impl<S: Source> Source for Resampler<S> {
         self.source.channel_count()
     }
 }
+
+#[inline]
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x_pi = x * std::f64::consts::PI;
+        x_pi.sin() / x_pi
+    }
+}
+
+#[inline]
+fn bessel_i0(x: f64) -> f64 {
+    // Just trust me on this one
+    let ax = x.abs();
+    if ax < 3.75 {
+        let y = (x / 3.75).powi(2);
+        1.0 + y * (3.5156229 + y * (3.0899424 + y * (1.2067492 + y * (0.2659732 + y * (0.0360768 + y * 0.0045813)))))
+    } else {
+        let y = 3.75 / ax;
+        (ax.exp() / ax.sqrt())
+            * (0.39894228
+                + y * (0.01328592
+                    + y * (0.00225319
+                        + y * (-0.00157565
+                            + y * (0.00916281
+                                + y * (-0.02057706 + y * (0.02635537 + y * (-0.01647633 + y * 0.00392377))))))))
+    }
+}
+
+#[inline]
+fn kaiser(k: f64) -> f64 {
+    if !(-1.0..=1.0).contains(&k) {
+        0.0
+    } else {
+        // 6.20426 is the Kaiser beta value for a rejection of 65 dB.
+        // The magic number at the end is bessel_i0(6.20426)
+        bessel_i0(6.20426 * (1.0 - k.powi(2)).sqrt()) / 81.0332923199
+    }
+}
@@ -0,0 +1,383 @@
+use super::{kaiser, sinc};
+use crate::{Sample, Source};
+use std::sync::{
+    Arc, OnceLock,
+    atomic::{AtomicU64, Ordering},
+};
+
+// The filter kernel is a Kaiser-windowed sinc which reaches this many zero crossings either side of its centre,
+// tabulated at this many points between each zero crossing. Points in between are interpolated.
+const ZERO_CROSSINGS: usize = 16;
+const PHASES: usize = 512;
+
+// Where the filter cuts off, as a fraction of whichever Nyquist frequency is lower, leaving room for the transition
+const CUTOFF: f64 = 0.95;
+
+/// The fastest playback rate a VariableResampler can be set to. Faster rates are clamped to this.
+pub const MAX_RATE: f64 = 4.0;
+
+// How long it takes a change of rate to mostly take effect (the time constant of the smoothing), in seconds
+const SMOOTHING_TIME: f64 = 0.005;
+
+// How many input frames are read from the Source at a time
+const CHUNK_FRAMES: usize = 256;
+
+static TABLE: OnceLock<Box<[f32]>> = OnceLock::new();
+
+/// A resampler whose ratio can be changed while it plays, for pitch, Doppler or slow-motion effects. Construct with
+/// `VariableResampler::new(source, source_rate, dest_rate)`, which behaves as a Source at the target sample rate, just
+/// like a Resampler, until its playback rate is changed.
+///
+/// The playback rate multiplies the speed at which the Source is played: 2.0 plays it twice as fast (and an octave
+/// higher), 0.5 plays it at half speed, and 1.0 plays it normally. It can be anything from 0.0, which holds still, up
+/// to `MAX_RATE`, and it doesn't have to be a neat fraction. Changes are smoothed over a few milliseconds, so they
+/// never click, whether they come from a RateHandle or from `set_rate()` between blocks.
+///
+/// This costs more than a Resampler, especially at high rates, as the filter widens to keep out aliasing.
+pub struct VariableResampler<S>
+where
+    S: Source,
+{
+    source: S,
+    channels: usize,
+    shared: Arc<RateShared>,
+
+    // The filter kernel, which is built by the first VariableResampler to be created, so never on the audio thread
+    table: &'static [f32],
+
+    // Input frames per output frame at a playback rate of 1.0
+    base_step: f64,
+
+    // The playback rate right now, part-way to the target, and how far it goes towards the target each frame
+    rate: f64,
+    smoothing: f64,
+
+    // How far the filter reaches either side at the current rate, and its scale, both in input frames
+    support: f64,
+    scale: f64,
+
+    // The furthest the filter can reach either side at any rate
+    max_support: usize,
+
+    // Interleaved input frames, starting at sample `read`, which is frame `input_start` of the Source. Frames before
+    // the start of the Source are silent. Samples before `read` are no longer needed, and are only moved out of the way
+    // once there are as many of them as there are samples still in use, so each sample is copied at most once or twice.
+    input: Vec<Sample>,
+    read: usize,
+    input_start: i64,
+
+    // The frame the Source ended at, once it has
+    end: Option<i64>,
+
+    // Where the next output frame is, in input frames, and which of its channels comes next
+    frame: i64,
+    phase: f64,
+    channel: usize,
+}
+
+/// A handle for changing a VariableResampler's playback rate from another thread. Get one with
+/// `VariableResampler::handle()`.
+///
+/// RateHandles are cheap to clone and can be sent between threads. Setting the rate is a single atomic store, so it
+/// never blocks.
+#[derive(Clone)]
+pub struct RateHandle(Arc<RateShared>);
+
+struct RateShared {
+    // The target playback rate, as f64 bits
+    target: AtomicU64,
+}
+
+impl<S: Source> VariableResampler<S> {
+    pub fn new(source: S, source_rate: u32, dest_rate: u32) -> Self {
+        assert!(source_rate != 0);
+        assert!(dest_rate != 0);
+
+        let channels = source.channel_count();
+        let base_step = f64::from(source_rate) / f64::from(dest_rate);
+        let max_support = (ZERO_CROSSINGS as f64 / filter_scale(base_step * MAX_RATE)).ceil() as usize + 1;
+
+        // Start with enough silence before the Source for the filter to reach back into
+        let mut input = Vec::with_capacity((max_support * 4 + CHUNK_FRAMES * 4) * channels);
+        input.resize(max_support * channels, 0.0);
+
+        let mut resampler = Self {
+            source,
+            channels,
+            shared: Arc::new(RateShared { target: AtomicU64::new(1.0f64.to_bits()) }),
+            table: table(),
+            base_step,
+            rate: 1.0,
+            smoothing: 1.0 - (-1.0 / (SMOOTHING_TIME * f64::from(dest_rate))).exp(),
+            support: 0.0,
+            scale: 0.0,
+            max_support,
+            input,
+            read: 0,
+            input_start: -(max_support as i64),
+            end: None,
+            frame: 0,
+            phase: 0.0,
+            channel: 0,
+        };
+        resampler.update_filter();
+        resampler
+    }
+
+    /// Returns a handle for changing the playback rate from another thread.
+    pub fn handle(&self) -> RateHandle {
+        RateHandle(self.shared.clone())
+    }
+
+    /// Returns the playback rate, or the rate it's heading towards if it's just been changed.
+    pub fn rate(&self) -> f64 {
+        f64::from_bits(self.shared.target.load(Ordering::Relaxed))
+    }
+
+    /// Sets the playback rate, which is clamped between 0.0 and `MAX_RATE`. The change is smoothed from the next
+    /// sample onwards.
+    pub fn set_rate(&mut self, rate: f64) {
+        self.shared.set_rate(rate);
+    }
+
+    /// Returns a reference to the Source being resampled.
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// Returns a mutable reference to the Source being resampled.
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    /// Consumes the VariableResampler, and returns the Source it was resampling.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    // Works out the filter's width for the current rate. Above the Source's own rate, the filter is stretched so that
+    // its cutoff follows the output's Nyquist frequency down, and it covers more input frames to match.
+    fn update_filter(&mut self) {
+        self.scale = filter_scale(self.base_step * self.rate);
+        self.support = ZERO_CROSSINGS as f64 / self.scale;
+    }
+
+    // Moves on to the next output frame, bringing the rate a little closer to its target
+    fn advance(&mut self) {
+        let target = f64::from_bits(self.shared.target.load(Ordering::Relaxed));
+        if self.rate != target {
+            self.rate += (target - self.rate) * self.smoothing;
+            if (target - self.rate).abs() < 1e-9 {
+                self.rate = target;
+            }
+            self.update_filter();
+        }
+
+        self.phase += self.base_step * self.rate;
+        let whole = self.phase.floor();
+        self.frame += whole as i64;
+        self.phase -= whole;
+    }
+
+    // Reads from the Source until the input reaches the given frame, or the Source ends
+    fn read_to(&mut self, frame: i64) {
+        while self.end.is_none() && self.input_end() <= frame {
+            // Throw away anything the filter can't reach any more
+            let discard = (self.frame - self.max_support as i64 - self.input_start).max(0) as usize;
+            self.read += discard * self.channels;
+            self.input_start += discard as i64;
+            if self.read >= self.input.len() - self.read {
+                self.input.copy_within(self.read.., 0);
+                self.input.truncate(self.input.len() - self.read);
+                self.read = 0;
+            }
+
+            let len = self.input.len();
+            self.input.resize(len + CHUNK_FRAMES * self.channels, 0.0);
+            let written = self.source.write_samples(&mut self.input[len..]);
+            self.input.truncate(len + written - written % self.channels);
+            if written < CHUNK_FRAMES * self.channels {
+                self.end = Some(self.input_end());
+            }
+        }
+    }
+
+    fn input_end(&self) -> i64 {
+        self.input_start + ((self.input.len() - self.read) / self.channels) as i64
+    }
+}
+
+impl<S: Source> Source for VariableResampler<S> {
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        let table = self.table;
+
+        for (i, s) in buffer.iter_mut().enumerate() {
+            let position = self.frame as f64 + self.phase;
+            let first = (position - self.support).floor() as i64 + 1;
+            let last = (position + self.support).floor() as i64;
+            if self.channel == 0 {
+                self.read_to(last);
+                if self.end.is_some_and(|end| self.frame >= end) {
+                    return i
+                }
+            }
+
+            // Add up every input frame the filter reaches, weighted by the filter at its distance from the output
+            let input = &self.input[self.read..];
+            let mut sum = 0.0;
+            for frame in first.max(self.input_start)..=last.min(self.input_end() - 1) {
+                let distance = (position - frame as f64).abs() * self.scale * PHASES as f64;
+                let index = distance as usize;
+                if let (Some(a), Some(b)) = (table.get(index), table.get(index + 1)) {
+                    let weight = f64::from(*a) + f64::from(b - a) * (distance - index as f64);
+                    let sample = input[(frame - self.input_start) as usize * self.channels + self.channel];
+                    sum += f64::from(sample) * weight;
+                }
+            }
+            *s = (sum * self.scale) as Sample;
+
+            self.channel += 1;
+            if self.channel == self.channels {
+                self.channel = 0;
+                self.advance();
+            }
+        }
+
+        buffer.len()
+    }
+
+    fn channel_count(&self) -> usize {
+        self.channels
+    }
+}
+
+impl RateHandle {
+    /// Returns the playback rate, or the rate it's heading towards if it's just been changed.
+    pub fn rate(&self) -> f64 {
+        f64::from_bits(self.0.target.load(Ordering::Relaxed))
+    }
+
+    /// Sets the playback rate, which is clamped between 0.0 and `MAX_RATE`. The change is smoothed from the
+    /// VariableResampler's next sample onwards.
+    pub fn set_rate(&self, rate: f64) {
+        self.0.set_rate(rate);
+    }
+}
+
+impl RateShared {
+    fn set_rate(&self, rate: f64) {
+        let rate = if rate.is_nan() { 0.0 } else { rate.clamp(0.0, MAX_RATE) };
+        self.target.store(rate.to_bits(), Ordering::Relaxed);
+    }
+}
+
+// The filter's scale for the given step (input frames per output frame): it's the cutoff, as a fraction of the input's
+// Nyquist frequency, and the filter is that many times wider
+fn filter_scale(step: f64) -> f64 {
+    CUTOFF * (1.0 / step).min(1.0)
+}
+
+// One side of the filter kernel, from its centre out to the last zero crossing, then a zero to interpolate towards
+fn table() -> &'static [f32] {
+    TABLE.get_or_init(|| {
+        let len = ZERO_CROSSINGS * PHASES;
+        (0..=len)
+            .map(|i| {
+                let x = i as f64 / PHASES as f64;
+                (sinc(x) * kaiser(x / ZERO_CROSSINGS as f64)) as f32
+            })
+            .chain(std::iter::once(0.0))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VariableResampler;
+    use crate::{Sample, Source};
+
+    // A mono ramp which counts up by 0.001 each frame, so the value heard says where in the Source it came from
+    struct Ramp {
+        frame: usize,
+        len: usize,
+    }
+
+    impl Source for Ramp {
+        fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+            let len = buffer.len().min(self.len - self.frame);
+            for s in &mut buffer[..len] {
+                *s = self.frame as Sample * 0.001;
+                self.frame += 1;
+            }
+            len
+        }
+
+        fn channel_count(&self) -> usize {
+            1
+        }
+    }
+
+    fn ramp(len: usize) -> Ramp {
+        Ramp { frame: 0, len }
+    }
+
+    // Plays to the end in uneven blocks, and checks that it stays ended
+    fn play_to_end(resampler: &mut VariableResampler<Ramp>) -> Vec<Sample> {
+        let mut output = Vec::new();
+        let mut block = vec![0.0; 100];
+        loop {
+            let written = resampler.write_samples(&mut block);
+            output.extend_from_slice(&block[..written]);
+            if written < block.len() {
+                break
+            }
+        }
+        assert_eq!(resampler.write_samples(&mut block), 0);
+        output
+    }
+
+    #[test]
+    fn output_length_follows_ratio() {
+        for (from, to) in [(48000, 48000), (44100, 48000), (48000, 44100), (96000, 22050)] {
+            let mut resampler = VariableResampler::new(ramp(10000), from, to);
+            let expected = 10000 * to as usize / from as usize;
+            let len = play_to_end(&mut resampler).len();
+            assert!(len.abs_diff(expected) <= 1, "{} to {}: {} frames, not {}", from, to, len, expected);
+        }
+    }
+
+    #[test]
+    fn drains_input_at_end() {
+        let output = play_to_end(&mut VariableResampler::new(ramp(1000), 48000, 48000));
+        assert_eq!(output.len(), 1000);
+
+        // Away from the edges, where the filter runs off the input, the ramp comes through unchanged
+        for (i, s) in output.iter().enumerate().take(980).skip(20) {
+            assert!((s - i as Sample * 0.001).abs() < 0.001, "frame {} is {}", i, s);
+        }
+    }
+
+    #[test]
+    fn rate_changes_mid_stream() {
+        let mut resampler = VariableResampler::new(ramp(20000), 48000, 48000);
+        let mut before = vec![0.0; 2000];
+        assert_eq!(resampler.write_samples(&mut before), before.len());
+        resampler.handle().set_rate(2.0);
+        let after = play_to_end(&mut resampler);
+
+        // The other 18000 frames take 9000 at double speed, plus about 120 more because the rate lags behind by the
+        // smoothing's time constant of 240 frames
+        let len = after.len();
+        assert!((9100..9140).contains(&len), "{} frames after the change", len);
+
+        // The ramp carries on without a jump, speeds up gradually, and ends up twice as steep
+        let mut last = before[before.len() - 1];
+        for s in &after[..len - 20] {
+            let step = s - last;
+            assert!(step > 0.0009 && step < 0.0021, "stepped by {}", step);
+            last = *s;
+        }
+        let slope = (after[len - 100] - after[len - 1100]) / 1000.0;
+        assert!((slope - 0.002).abs() < 0.00001, "slope is {}", slope);
+    }
+}
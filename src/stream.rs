@@ -1,115 +1,1116 @@
-use crate::{Error, Sample, Source};
-use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    BuildStreamError, PlayStreamError, SampleFormat, SupportedStreamConfigsError,
+use crate::{
+    Error, Sample, Source,
+    backend::{Backend, BackendStream, CallbackInfo},
+    clock::{ClockShared, StreamClock},
+    convert::Dither,
+    device::{SampleFormat, SupportedConfig},
+    queue::Queue,
+    remix::{ChannelLayout, RemixMatrix},
+    resampler::Resampler,
 };
-use std::sync::{Arc, Mutex};
+#[cfg(feature = "cpal")]
+use crate::{
+    backend::CpalBackend,
+    device::{Device, Host},
+};
+use std::{
+    any::Any,
+    cell::UnsafeCell,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicPtr, AtomicU8, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// What to aim for when nothing else has been asked for
+const DEFAULT_SAMPLE_RATE: u32 = 48000;
+const DEFAULT_CHANNELS: u16 = 2;
+
+// How often a stream checks whether it should move to a different device
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often events are passed to the event callback, if there is one
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// How many StreamEvents can wait to be read before any more are dropped
+const EVENT_QUEUE_SIZE: usize = 64;
+
+// How long pausing, resuming and stopping take to fade, to avoid clicks
+const FADE_TIME: Duration = Duration::from_millis(10);
+
+// The longest a dropped stream will wait for its fade-out to finish
+const STOP_TIMEOUT: Duration = Duration::from_millis(100);
+
+// How many calls to `with_source()` can be waiting for the audio thread at once
+const COMMAND_QUEUE_SIZE: usize = 64;
+
+// How often something waiting for the Source checks whether it's free
+const SOURCE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// The longest `with_source()` waits for the audio thread to run its closure
+const SOURCE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Where a call to `with_source()` has got to
+const CALL_PENDING: u8 = 0;
+const CALL_RUNNING: u8 = 1;
+const CALL_DONE: u8 = 2;
+const CALL_CANCELLED: u8 = 3;
+
+// The most frames a device is expected to ask for at once if it's left to choose its own buffer size. Working buffers
+// are allocated this big up front, so that they don't have to grow on the audio thread.
+const EXPECTED_BUFFER_SIZE: usize = 8192;
 
 /// An audio output stream which plays audio sources. Must be used with a Source object.
 /// This object will be queried for samples to be played directly to the output device.
+///
+/// The stream looks after its device on a background thread. If the device is lost, such as when a headset is
+/// unplugged, the stream moves to the next device the OutputStreamBuilder would have picked (usually the system's
+/// default), and keeps playing the same Source. It also follows changes to the system's default device if no device
+/// was chosen, and moves back to the chosen device if it comes back. If the new device has a different sample rate
+/// or channel count, the Source is resampled or remixed to fit, so `sample_rate` and `channel_count` never change.
+/// Each of these is reported as a StreamEvent, read with `poll_event()` or passed to a callback set with
+/// `set_event_callback()`, along with any errors, underruns, or panics in the Source.
+///
+/// The Source belongs to the audio thread, which never waits for a lock, so nothing the rest of the program does can
+/// hold up playback. The Source can still be reached with `with_source()` and `replace_source()`, which pass the work
+/// to the audio thread to do between buffers.
+///
+/// If the Source panics, the stream catches it and plays silence from then on, rather than taking the audio thread
+/// down with it. Replacing the Source with `replace_source()` starts it playing again.
+///
+/// The stream can be paused and resumed, and it fades out briefly before pausing or being dropped, to avoid a click.
+/// While it's paused, the Source isn't asked for any samples.
 pub struct OutputStream<S>
 where
     S: Source + Send + 'static,
 {
-    _stream: cpal::Stream,
-    _source: Arc<Mutex<S>>,
+    link: Arc<SourceLink<S>>,
+    shared: Arc<StreamShared>,
+    control: Sender<Control>,
+    thread: Option<JoinHandle<()>>,
+
+    /// The sample rate the Source was set up for.
     pub sample_rate: u32,
+
+    /// The channel count the Source was set up for.
     pub channel_count: u16,
 }
 
+/// Opens an OutputStream on a particular backend, host or device. Construct with `OutputStreamBuilder::new()`, then
+/// call `build()` once everything's set. Anything left unset uses the system's default.
+///
+/// Streams play through the system's default host using cpal, unless another host is chosen with `host()`, or
+/// another Backend entirely with `backend()`. Without the "cpal" feature there's no default, so a backend has to be
+/// given, or `build()` will return `Error::HostUnavailable`.
+///
+/// If the chosen device can't be found when the stream is opened (for example, because it's been unplugged since it
+/// was listed), the builder tries the fallback device if there is one, then the default device of the same host,
+/// unless told not to with `fallback()`. `OutputStream::device_name()` says which device was actually opened.
+/// The same rules are used to pick a new device if the stream's device is lost while it's playing.
+///
+/// The sample rate, channel count, sample format and buffer size can also be requested. Devices only support certain
+/// combinations of these, so the builder scores each configuration the device supports and opens the closest one,
+/// which `OutputStream::config()` then reports. A matching channel count matters most, then sample rate, then format.
+/// Without any requests, it aims for 48 kHz stereo in f32, with the device's default buffer size.
+#[derive(Clone, Debug)]
+pub struct OutputStreamBuilder {
+    backend: Option<Arc<dyn Backend>>,
+    device: Option<String>,
+    fallback_device: Option<String>,
+    fallback: bool,
+    sample_rate: Option<u32>,
+    channels: Option<u16>,
+    sample_format: Option<SampleFormat>,
+    buffer_size: Option<u32>,
+    latency: Option<Duration>,
+    dither: Dither,
+}
+
+/// The configuration an OutputStream was actually opened with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamConfig {
+    /// The number of channels.
+    pub channels: u16,
+
+    /// The sample rate, in Hz.
+    pub sample_rate: u32,
+
+    /// The format of the samples sent to the device.
+    pub sample_format: SampleFormat,
+
+    /// The buffer size, in frames, or None if the device's default was used.
+    pub buffer_size: Option<u32>,
+
+    /// The dither added when samples are converted to an integer format for the device. It isn't used for f32.
+    pub dither: Dither,
+}
+
+/// Something that happened to an OutputStream while it was playing, reported by `OutputStream::poll_event()`.
+#[derive(Clone, Debug)]
+pub enum StreamEvent {
+    /// The device stopped working, usually because it was unplugged. Nothing can be heard until the stream finds
+    /// another device, which it will keep trying to do.
+    DeviceLost { device: String },
+
+    /// The stream moved to a different device, because the old one was lost, the system's default device changed,
+    /// or the chosen device came back.
+    DeviceChanged { device: String, config: StreamConfig },
+
+    /// The device asked for samples later than expected, so there was probably an audible gap. `gap` is roughly how
+    /// long it was. This usually means the Source, or something else on the system, is taking too long.
+    Underrun { gap: Duration },
+
+    /// The Source panicked with the given message. The stream will play silence until the Source is replaced.
+    SourcePanicked { message: String },
+
+    /// The audio backend reported an error. The stream carries on if it can.
+    Error(Error),
+}
+
+// State shared between an OutputStream, its manager thread and its Renderers
+pub(crate) struct StreamShared {
+    current: Mutex<Current>,
+    pub(crate) events: Queue<StreamEvent>,
+    pub(crate) clock: Arc<ClockShared>,
+    callback: Mutex<Option<EventCallback>>,
+
+    // Whether the stream should be paused, and whether it has finished fading out
+    paused: AtomicBool,
+    silent: AtomicBool,
+
+    // Whether the Source has panicked since it was last replaced
+    panicked: AtomicBool,
+}
+
+struct Current {
+    device: Option<String>,
+    config: StreamConfig,
+}
+
+type EventCallback = Box<dyn FnMut(StreamEvent) + Send>;
+
+enum Control {
+    Stop,
+    Reopen,
+    Error(Error),
+}
+
+// Owns the backend's stream, which might not be able to move between threads, and moves it between devices as needed
+struct Manager<S>
+where
+    S: Source + Send + 'static,
+{
+    builder: OutputStreamBuilder,
+    backend: Arc<dyn Backend>,
+    link: Arc<SourceLink<S>>,
+    channels: u16,
+    sample_rate: u32,
+    shared: Arc<StreamShared>,
+    control: Sender<Control>,
+    stream: Option<Box<dyn BackendStream>>,
+    device: Option<String>,
+}
+
+// Pulls samples from the Source, resampling and remixing them to fit the device if its format doesn't match.
+// OfflineRenderer drives one of these too, so it renders exactly what a device would have been sent.
+pub(crate) struct Renderer<S>
+where
+    S: Source,
+{
+    source: Root<S>,
+    matrix: Option<RemixMatrix>,
+    scratch: Vec<Sample>,
+    shared: Arc<StreamShared>,
+    sample_rate: u32,
+    channels: usize,
+
+    // When the last buffer is due to be played, and how many frames it had, for spotting underruns
+    last_buffer: Option<(Duration, usize)>,
+
+    // For the clock: the Source's sample rate, the clock's frame count when this Renderer took over, how many frames
+    // of the device's it has rendered from the Source since then, and the latency of the last buffer
+    source_rate: u32,
+    base_frame: u64,
+    rendered: u64,
+    latency: Duration,
+
+    // Gain applied at the end of the last buffer, and how much it changes by per frame while fading
+    gain: Sample,
+    fade_step: Sample,
+}
+
+// The Source, and the Resampler around it if it needs one
+enum Root<S>
+where
+    S: Source,
+{
+    Direct(S),
+    Resampled(Resampler<S>),
+}
+
+// Passes the Source between whoever's playing it, without locks. While a Renderer is playing the Source, the
+// Renderer owns it outright, and other threads reach it by queueing commands which the Renderer runs between buffers.
+// Otherwise it's left in the slot, where the next Renderer, or anything waiting on a command, can pick it up.
+// Commands which have been run are sent back through the garbage queue, so they're never dropped on the audio thread.
+struct SourceLink<S> {
+    slot: AtomicPtr<S>,
+    commands: Queue<Command<S>>,
+    garbage: Queue<Command<S>>,
+    _source: PhantomData<S>,
+}
+
+type Command<S> = Box<dyn Call<S>>;
+
+// A queued call to `with_source()`. It's run through a reference, so that the box is left whole to be sent back.
+trait Call<S>: Send {
+    fn call(&mut self, source: &mut S);
+}
+
+// The closure passed to `with_source()`, and where to leave what it returns
+struct Request<F, R> {
+    f: Option<F>,
+    reply: Arc<Reply<R>>,
+}
+
+// Somewhere for the audio thread to leave the result of a call to `with_source()`, set up before the call is queued.
+// Whoever moves `state` on from CALL_PENDING owns `result` until it's set to CALL_DONE, after which the caller does.
+struct Reply<R> {
+    state: AtomicU8,
+    result: UnsafeCell<Option<thread::Result<R>>>,
+}
+
+// Plays the Source for a backend stream, and gives it back to the SourceLink once the stream's closed
+struct StreamRenderer<S>
+where
+    S: Source + Send + 'static,
+{
+    renderer: Option<Renderer<S>>,
+    link: Arc<SourceLink<S>>,
+}
+
 impl<S> OutputStream<S>
 where
     S: Source + Send + 'static,
 {
-    /// Sets up and returns an OutputStream. Takes a closure which returns a Source, which will be used for
-    /// continuous playback until the OutputStream is dropped.
+    /// Sets up and returns an OutputStream on the default device. Takes a closure which returns a Source, which will
+    /// be used for continuous playback until the OutputStream is dropped.
     /// The params to the closure are (u16, u32) which represent the output's channel count and sample rate.
+    ///
+    /// This is a shorthand for `OutputStreamBuilder::new().build()`.
     pub fn with<F>(mixer_setup: F) -> Result<Self, Error>
     where
         F: FnOnce(u16, u32) -> S,
     {
-        let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
+        OutputStreamBuilder::new().build(mixer_setup)
+    }
+
+    /// Returns the name of the device this stream is playing on, or None if its device was lost and it hasn't found
+    /// another one yet.
+    pub fn device_name(&self) -> Option<String> {
+        self.shared.current.lock().unwrap().device.clone()
+    }
+
+    /// Returns the configuration of the device this stream is playing on, or was last playing on.
+    pub fn config(&self) -> StreamConfig {
+        self.shared.current.lock().unwrap().config
+    }
+
+    /// Returns the oldest event that hasn't been read yet, or None if there aren't any. This never blocks.
+    ///
+    /// Events are held in a fixed-size queue, and any that happen while it's full are lost, so this should be called
+    /// regularly (eg. once per frame) until it returns None.
+    pub fn poll_event(&self) -> Option<StreamEvent> {
+        self.shared.events.pop()
+    }
+
+    /// Sets a callback to be called with each event as it happens, instead of leaving them for `poll_event()`.
+    /// It's called on the stream's background thread, never the audio thread, so it's free to block or allocate.
+    /// This replaces any callback that was already set.
+    pub fn set_event_callback(&self, callback: impl FnMut(StreamEvent) + Send + 'static) {
+        *self.shared.callback.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    /// Removes the event callback, so that events are left for `poll_event()` again.
+    pub fn remove_event_callback(&self) {
+        *self.shared.callback.lock().unwrap() = None;
+    }
+
+    /// Returns the stream's playback clock, which says which of the Source's frames is being heard at any moment.
+    pub fn clock(&self) -> StreamClock {
+        StreamClock(self.shared.clock.clone())
+    }
+
+    /// Pauses playback, after a short fade-out. The device is left open, but the Source isn't asked for any more
+    /// samples until `resume()` is called.
+    pub fn pause(&self) {
+        self.shared.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Resumes playback from where it was paused, with a short fade-in.
+    pub fn resume(&self) {
+        self.shared.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// Returns true if the stream has been paused.
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused.load(Ordering::Relaxed)
+    }
+
+    /// Calls `f` with the Source, such as to inspect or change its settings, and returns whatever `f` returns.
+    ///
+    /// `f` is run by the audio thread before it renders its next buffer, and this waits until it has. If nothing is
+    /// playing at the moment (for example, because the device has been lost), `f` is run on this thread instead.
+    /// Either way, `f` holds up the audio thread while it runs, so it should be quick, or the output will skip.
+    /// Anything which needs to be done often is better done through a lock-free handle, like a MixerHandle.
+    ///
+    /// If the audio thread doesn't get round to `f` within a second, because the backend has stopped asking for
+    /// samples without closing the stream, `f` is dropped without being called, and `Error::Timeout` is returned.
+    /// With a PullBackend, the audio thread is whichever thread calls `PullBackend::render()`, so calling this from
+    /// that thread always times out. If `f` panics, so does this.
+    pub fn with_source<R, F>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut S) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.link.collect_garbage();
+        let deadline = Instant::now() + SOURCE_TIMEOUT;
+        let reply = Arc::new(Reply { state: AtomicU8::new(CALL_PENDING), result: UnsafeCell::new(None) });
+        let mut command: Command<S> = Box::new(Request { f: Some(f), reply: reply.clone() });
+        while let Err(rejected) = self.link.commands.push(command) {
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout)
+            }
+            command = rejected;
+            thread::sleep(SOURCE_POLL_INTERVAL);
+        }
+
+        while reply.state.load(Ordering::Acquire) != CALL_DONE {
+            if let Some(mut source) = self.link.take() {
+                self.link.run_commands(&mut source);
+                self.link.put(source);
+            } else if Instant::now() >= deadline && reply.cancel() {
+                // The command is still queued, and whoever runs the queue next will send it back to be dropped
+                return Err(Error::Timeout)
+            } else {
+                thread::sleep(SOURCE_POLL_INTERVAL);
+            }
+        }
+        self.link.collect_garbage();
+
+        // SAFETY: the call is done, so the result has been left behind and nothing else will touch it
+        match unsafe { (*reply.result.get()).take() } {
+            Some(Ok(result)) => Ok(result),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => unreachable!(),
+        }
+    }
+
+    /// Replaces the Source with a new one, and returns the old one. The new Source should have the same sample rate
+    /// and channel count as the old one, which are given by `sample_rate` and `channel_count`.
+    ///
+    /// If the old Source had panicked, the new one starts playing. If the audio thread doesn't respond in time, as
+    /// with `with_source()`, the new Source is dropped and `Error::Timeout` is returned.
+    pub fn replace_source(&self, source: S) -> Result<S, Error> {
+        let old = self.with_source(move |old| std::mem::replace(old, source))?;
+        if self.shared.panicked.swap(false, Ordering::Relaxed) {
+            // The stream was set up without a resampler for the old Source, so set it up again
+            let _ = self.control.send(Control::Reopen);
+        }
+        Ok(old)
+    }
+}
+
+impl<S> Drop for OutputStream<S>
+where
+    S: Source + Send + 'static,
+{
+    fn drop(&mut self) {
+        // Give the stream a chance to fade out, unless it has nothing to play on
+        self.pause();
+        let start = Instant::now();
+        while self.device_name().is_some()
+            && !self.shared.silent.load(Ordering::Relaxed)
+            && start.elapsed() < STOP_TIMEOUT
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let _ = self.control.send(Control::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl OutputStreamBuilder {
+    /// Creates a builder which will open the default device on the default host.
+    pub fn new() -> Self {
+        Self {
+            backend: None,
+            device: None,
+            fallback_device: None,
+            fallback: true,
+            sample_rate: None,
+            channels: None,
+            sample_format: None,
+            buffer_size: None,
+            latency: None,
+            dither: Dither::Triangular,
+        }
+    }
+
+    /// Opens the stream on the given backend's default device, or on one of its devices chosen with `device_name()`.
+    pub fn backend(mut self, backend: impl Backend + 'static) -> Self {
+        self.backend = Some(Arc::new(backend));
+        self
+    }
+
+    /// Opens the stream on the given host's default device, or on a device from this host chosen with `device_name()`.
+    /// This is a shorthand for `backend(CpalBackend::new(host))`.
+    #[cfg(feature = "cpal")]
+    pub fn host(self, host: Host) -> Self {
+        self.backend(CpalBackend::new(host))
+    }
+
+    /// Opens the stream on the given device, and its host.
+    #[cfg(feature = "cpal")]
+    pub fn device(self, device: &Device) -> Self {
+        self.host(device.host()).device_name(device.name())
+    }
+
+    /// Opens the stream on the device with the given name. This is useful for remembering a device across runs,
+    /// for example in a game's settings file.
+    pub fn device_name(mut self, name: &str) -> Self {
+        self.device = Some(name.into());
+        self
+    }
+
+    /// Sets a device to try if the chosen device can't be found, before falling back to the host's default device.
+    #[cfg(feature = "cpal")]
+    pub fn fallback_device(self, device: &Device) -> Self {
+        self.fallback_device_name(device.name())
+    }
+
+    /// Sets the name of a device to try if the chosen device can't be found, before falling back to the default device.
+    pub fn fallback_device_name(mut self, name: &str) -> Self {
+        self.fallback_device = Some(name.into());
+        self
+    }
+
+    /// Sets whether to fall back to the host's default device if the chosen device can't be found. This is on by
+    /// default. If it's off, `build()` will return `Error::DeviceNotAvailable` instead.
+    pub fn fallback(mut self, fallback: bool) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    /// Asks for the given sample rate, in Hz. If the device doesn't support it, the closest rate it does support is
+    /// used.
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = Some(sample_rate);
+        self
+    }
+
+    /// Asks for the given number of channels. If the device doesn't support it, the closest count above it is
+    /// preferred over any below it.
+    pub fn channels(mut self, channels: u16) -> Self {
+        self.channels = Some(channels);
+        self
+    }
+
+    /// Asks for the given sample format.
+    pub fn sample_format(mut self, sample_format: SampleFormat) -> Self {
+        self.sample_format = Some(sample_format);
+        self
+    }
+
+    /// Asks for the given buffer size, in frames, which is clamped to what the device supports. Smaller buffers mean
+    /// lower latency, but a higher risk of audio dropping out if the Source can't keep up.
+    /// If the device can't report what buffer sizes it supports, its default is used instead.
+    pub fn buffer_size(mut self, frames: u32) -> Self {
+        self.buffer_size = Some(frames);
+        self.latency = None;
+        self
+    }
+
+    /// Asks for a buffer size which gives the given latency at whichever sample rate is chosen.
+    /// This replaces any size set with `buffer_size()`.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = Some(latency);
+        self.buffer_size = None;
+        self
+    }
+
+    /// Sets the dither added to samples when the device wants them in an integer format, rather than f32.
+    /// Triangular dither is used by default. See the `convert` module.
+    pub fn dither(mut self, dither: Dither) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    /// Opens the stream. Takes a closure which returns a Source, which will be used for continuous playback until the
+    /// OutputStream is dropped. The params to the closure are the output's channel count and sample rate.
+    pub fn build<S, F>(&self, mixer_setup: F) -> Result<OutputStream<S>, Error>
+    where
+        S: Source + Send + 'static,
+        F: FnOnce(u16, u32) -> S,
+    {
+        let backend = self.resolve_backend()?;
+        let device = self.find_device(&*backend, None)?;
+        let config = self.choose_config(&backend.supported_configs(&device)?).ok_or(Error::DeviceNotUsable)?;
+        let (channels, sample_rate) = (config.channels, config.sample_rate);
+        let link = Arc::new(SourceLink::new(mixer_setup(channels, sample_rate)));
+        let shared = Arc::new(StreamShared::new(config, sample_rate));
 
-        let host = cpal::default_host();
-        let device = match host.default_output_device() {
-            Some(d) => d,
-            None => return Err(Error::NoOutputDevice),
+        // Any later device should match the Source's format if it can
+        let builder = self.clone().sample_rate(sample_rate).channels(channels);
+        let (control, receiver) = mpsc::channel();
+        let (ready_sender, ready) = mpsc::channel();
+        let thread = {
+            let (link, shared, control) = (link.clone(), shared.clone(), control.clone());
+            thread::spawn(move || {
+                let mut manager = Manager {
+                    builder,
+                    backend,
+                    link,
+                    channels,
+                    sample_rate,
+                    shared,
+                    control,
+                    stream: None,
+                    device: None,
+                };
+                let result = manager.open(device, config);
+                let ok = result.is_ok();
+                let _ = ready_sender.send(result);
+                if ok {
+                    manager.run(receiver);
+                }
+            })
         };
+        if let Err(e) = ready.recv().unwrap_or(Err(Error::DeviceNotAvailable)) {
+            let _ = thread.join();
+            return Err(e)
+        }
+
+        Ok(OutputStream { link, shared, control, thread: Some(thread), sample_rate, channel_count: channels })
+    }
 
-        let mut supported_configs_range = match device.supported_output_configs() {
-            Ok(r) => r,
-            Err(SupportedStreamConfigsError::DeviceNotAvailable) => return Err(Error::DeviceNotAvailable),
-            Err(SupportedStreamConfigsError::InvalidArgument) => return Err(Error::InvalidArgument),
-            Err(SupportedStreamConfigsError::BackendSpecific { err }) => return Err(Error::CPALError(err)),
+    // Picks the supported configuration closest to what was asked for
+    fn choose_config(&self, supported: &[SupportedConfig]) -> Option<StreamConfig> {
+        let want_channels = self.channels.unwrap_or(DEFAULT_CHANNELS);
+        let want_rate = self.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+        let format_rank = |format: SampleFormat| match self.sample_format {
+            Some(wanted) if wanted == format => 0,
+            _ => match format {
+                SampleFormat::F32 => 1,
+                SampleFormat::I16 => 2,
+                SampleFormat::U16 => 3,
+            },
         };
-        let supported_config = match supported_configs_range.next() {
-            Some(c) => c,
-            None => return Err(Error::DeviceNotUsable),
+
+        supported
+            .iter()
+            .map(|range| {
+                let sample_rate = want_rate.clamp(*range.sample_rates.start(), *range.sample_rates.end());
+                let wanted_size = self.buffer_size.or_else(|| {
+                    self.latency.map(|latency| (latency.as_secs_f64() * f64::from(sample_rate)).round() as u32)
+                });
+                let buffer_size = match (&range.buffer_sizes, wanted_size) {
+                    (Some(sizes), Some(frames)) => Some(frames.clamp(*sizes.start(), *sizes.end())),
+                    _ => None,
+                };
+                let config = StreamConfig {
+                    channels: range.channels,
+                    sample_rate,
+                    sample_format: range.sample_format,
+                    buffer_size,
+                    dither: self.dither,
+                };
+
+                // Losing channels is worse than having spare ones, which are just left silent
+                let channel_score = if range.channels >= want_channels {
+                    u32::from(range.channels - want_channels)
+                } else {
+                    u32::from(want_channels - range.channels) + u32::from(u16::MAX)
+                };
+                let score = (channel_score, sample_rate.abs_diff(want_rate), format_rank(range.sample_format));
+                (score, config)
+            })
+            .min_by_key(|(score, _)| *score)
+            .map(|(_, config)| config)
+    }
+
+    // Returns the backend to play on, which is cpal's default host unless another was chosen
+    fn resolve_backend(&self) -> Result<Arc<dyn Backend>, Error> {
+        match &self.backend {
+            Some(backend) => Ok(backend.clone()),
+            #[cfg(feature = "cpal")]
+            None => Ok(Arc::new(CpalBackend::default())),
+            #[cfg(not(feature = "cpal"))]
+            None => Err(Error::HostUnavailable),
         }
-        .with_max_sample_rate();
+    }
 
-        let sample_rate = supported_config.sample_rate().0;
-        let channel_count: u16 = supported_config.channels();
+    // Finds the chosen device, or the fallback device, or the default one if none was chosen or they're gone and
+    // falling back is allowed. The device a stream is `current`ly open on always counts as available: some backends
+    // leave devices which are in use out of the list, and losing it is reported by the stream itself.
+    fn find_device(&self, backend: &dyn Backend, current: Option<&str>) -> Result<String, Error> {
+        if self.device.is_some() || self.fallback_device.is_some() {
+            let devices = match backend.output_devices() {
+                Ok(devices) => devices,
+                Err(e) if !self.fallback => return Err(e),
+                Err(_) => Vec::new(),
+            };
+            for name in self.device.iter().chain(self.fallback_device.iter()) {
+                if current == Some(name.as_str()) || devices.contains(name) {
+                    return Ok(name.clone())
+                }
+            }
+            if !self.fallback {
+                return Err(Error::DeviceNotAvailable)
+            }
+        }
+        backend.default_output_device().ok_or(Error::NoOutputDevice)
+    }
+}
 
-        let source = Arc::new(Mutex::new(mixer_setup(channel_count, sample_rate)));
+impl Default for OutputStreamBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamConfig {
+    /// Creates a config with the given channel count and sample rate, in f32, with no particular buffer size, and
+    /// triangular dither.
+    /// This is mostly useful for OfflineRenderer.
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        Self { channels, sample_rate, sample_format: SampleFormat::F32, buffer_size: None, dither: Dither::Triangular }
+    }
+
+    // How many samples the device is likely to ask for at most in one go, for allocating working buffers
+    pub(crate) fn expected_buffer_samples(&self) -> usize {
+        self.buffer_size.map_or(EXPECTED_BUFFER_SIZE, |size| size as usize) * usize::from(self.channels)
+    }
+}
+
+impl StreamShared {
+    // `sample_rate` is the Source's, which the clock counts frames at
+    pub(crate) fn new(config: StreamConfig, sample_rate: u32) -> Self {
+        Self {
+            current: Mutex::new(Current { device: None, config }),
+            events: Queue::with_capacity(EVENT_QUEUE_SIZE),
+            clock: Arc::new(ClockShared::new(sample_rate)),
+            callback: Mutex::new(None),
+            paused: AtomicBool::new(false),
+            silent: AtomicBool::new(false),
+            panicked: AtomicBool::new(false),
+        }
+    }
 
-        let f32_source = source.clone();
-        let write_f32 = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            data.iter_mut().for_each(|s| *s = 0.0);
-            f32_source.lock().unwrap().write_samples(data);
+    // Silences the Source until it's replaced, and reports the panic
+    fn source_panicked(&self, payload: Box<dyn Any + Send>) {
+        let message = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+            (Some(message), _) => (*message).to_owned(),
+            (_, Some(message)) => message.clone(),
+            _ => String::from("unknown panic"),
         };
+        self.panicked.store(true, Ordering::Relaxed);
+        let _ = self.events.push(StreamEvent::SourcePanicked { message });
+    }
+}
 
-        let i16_source = source.clone();
-        let mut i16_buf: Vec<Sample> = Vec::new();
-        let write_i16 = move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
-            i16_buf.clear();
-            i16_buf.reserve(data.len());
-            unsafe {
-                i16_buf.set_len(data.len());
+impl<S> Manager<S>
+where
+    S: Source + Send + 'static,
+{
+    fn run(mut self, receiver: Receiver<Control>) {
+        let mut last_check = Instant::now();
+        loop {
+            match receiver.recv_timeout(EVENT_POLL_INTERVAL) {
+                Ok(Control::Stop) | Err(RecvTimeoutError::Disconnected) => break,
+                Ok(Control::Reopen) => {
+                    self.check_device(true);
+                    last_check = Instant::now();
+                },
+                Ok(Control::Error(Error::DeviceNotAvailable)) => {
+                    self.lose_device();
+                    self.check_device(false);
+                    last_check = Instant::now();
+                },
+                Ok(Control::Error(err)) => {
+                    let _ = self.shared.events.push(StreamEvent::Error(err));
+                },
+                Err(RecvTimeoutError::Timeout) => (),
             }
-            i16_buf.iter_mut().for_each(|s| *s = 0.0);
-            i16_source.lock().unwrap().write_samples(&mut i16_buf);
-            for (in_sample, out_sample) in i16_buf.iter().zip(data.iter_mut()) {
-                *out_sample = (in_sample * f32::from(i16::MAX)) as i16;
+            if last_check.elapsed() >= DEVICE_POLL_INTERVAL {
+                self.check_device(false);
+                last_check = Instant::now();
             }
-        };
+            if let Some(callback) = self.shared.callback.lock().unwrap().as_mut() {
+                while let Some(event) = self.shared.events.pop() {
+                    callback(event);
+                }
+            }
+        }
+    }
 
-        let u16_source = source.clone();
-        let mut u16_buf: Vec<Sample> = Vec::new();
-        let write_u16 = move |data: &mut [u16], _: &cpal::OutputCallbackInfo| {
-            u16_buf.clear();
-            u16_buf.reserve(data.len());
-            unsafe {
-                u16_buf.set_len(data.len());
+    fn open(&mut self, device: String, config: StreamConfig) -> Result<(), Error> {
+        // The last stream has been closed by now, but someone else might be running a command on the Source. If it
+        // never comes back, the old stream's backend is still holding on to it.
+        let deadline = Instant::now() + SOURCE_TIMEOUT;
+        let source = loop {
+            match self.link.take() {
+                Some(source) => break *source,
+                None if Instant::now() >= deadline => return Err(Error::Timeout),
+                None => thread::sleep(SOURCE_POLL_INTERVAL),
             }
-            u16_buf.iter_mut().for_each(|s| *s = 0.0);
-            u16_source.lock().unwrap().write_samples(&mut u16_buf);
-            for (in_sample, out_sample) in u16_buf.iter().zip(data.iter_mut()) {
-                *out_sample = ((in_sample + 1.0) * f32::from(i16::MAX)) as u16;
+        };
+        let renderer = Renderer::new(source, self.channels, self.sample_rate, config, self.shared.clone());
+        let mut renderer = StreamRenderer { renderer: Some(renderer), link: self.link.clone() };
+        let control = self.control.clone();
+        let stream = self.backend.open(
+            &device,
+            config,
+            Box::new(move |buffer, info| renderer.render(buffer, info)),
+            Box::new(move |err| {
+                let _ = control.send(Control::Error(err));
+            }),
+        )?;
+        self.stream = Some(stream);
+        self.device = Some(device);
+        *self.shared.current.lock().unwrap() = Current { device: self.device.clone(), config };
+        Ok(())
+    }
+
+    fn lose_device(&mut self) {
+        if let Some(device) = self.device.take() {
+            self.stream = None;
+            self.shared.current.lock().unwrap().device = None;
+            let _ = self.shared.events.push(StreamEvent::DeviceLost { device });
+        }
+    }
+
+    // Moves to whichever device the builder would choose now, if that's not the one already in use: a preferred
+    // device which has come back, or a new default device. With `reopen`, the stream is opened again even if it's
+    // already on that device.
+    fn check_device(&mut self, reopen: bool) {
+        // Nothing is preferred over the chosen device, so there's no need to look while the stream's on it
+        if !reopen && self.device.is_some() && self.device == self.builder.device {
+            return
+        }
+        let device = match self.builder.find_device(&*self.backend, self.device.as_deref()) {
+            Ok(device) => device,
+            Err(_) => return,
+        };
+        let changed = self.device.as_ref() != Some(&device);
+        if !changed && !reopen {
+            return
+        }
+        let configs = self.backend.supported_configs(&device).ok();
+        let config = match configs.and_then(|configs| self.builder.choose_config(&configs)) {
+            Some(config) => config,
+            None => return,
+        };
+
+        // Some backends can't open a device that's still in use, so the old stream has to go first
+        self.stream = None;
+        let name = device.clone();
+        match self.open(device, config) {
+            Ok(()) if changed => {
+                let _ = self.shared.events.push(StreamEvent::DeviceChanged { device: name, config });
+            },
+            Ok(()) => (),
+            // Only report the first failure, rather than one every time the device is checked
+            Err(e) => {
+                if let Some(device) = self.device.take() {
+                    self.shared.current.lock().unwrap().device = None;
+                    let _ = self.shared.events.push(StreamEvent::DeviceLost { device });
+                    let _ = self.shared.events.push(StreamEvent::Error(e));
+                }
+            },
+        }
+    }
+}
+
+impl<S> Renderer<S>
+where
+    S: Source,
+{
+    pub(crate) fn new(
+        source: S,
+        channels: u16,
+        sample_rate: u32,
+        config: StreamConfig,
+        shared: Arc<StreamShared>,
+    ) -> Self {
+        let gain = if shared.paused.load(Ordering::Relaxed) { 0.0 } else { 1.0 };
+        let base_frame = shared.clock.frames();
+
+        // The resampler asks the Source for samples right away, so it's guarded just like `render()`. A Source which
+        // has panicked before can't be trusted, so the resampler starts with silence instead.
+        let source = if sample_rate != config.sample_rate {
+            let mut resampler = Resampler::unprimed(source, sample_rate, config.sample_rate);
+            if !shared.panicked.load(Ordering::Relaxed) {
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| resampler.prime())) {
+                    shared.source_panicked(payload);
+                }
             }
+            Root::Resampled(resampler)
+        } else {
+            Root::Direct(source)
+        };
+        let matrix = if channels != config.channels {
+            let from = ChannelLayout::from_channel_count(channels.into());
+            let to = ChannelLayout::from_channel_count(config.channels.into());
+            Some(RemixMatrix::between(from, to))
+        } else {
+            None
+        };
+        let scratch = match &matrix {
+            Some(matrix) => {
+                Vec::with_capacity(config.expected_buffer_samples() / matrix.output_count() * matrix.input_count())
+            },
+            None => Vec::new(),
         };
+        Self {
+            source,
+            matrix,
+            scratch,
+            shared,
+            sample_rate: config.sample_rate,
+            channels: config.channels.into(),
+            last_buffer: None,
+            source_rate: sample_rate,
+            base_frame,
+            rendered: 0,
+            latency: Duration::ZERO,
+            gain,
+            fade_step: 1.0 / (FADE_TIME.as_secs_f32() * config.sample_rate as Sample).max(1.0),
+        }
+    }
 
-        let sample_format = supported_config.sample_format();
-        let config = supported_config.into();
-        let stream = match match sample_format {
-            SampleFormat::F32 => device.build_output_stream(&config, write_f32, err_fn),
-            SampleFormat::I16 => device.build_output_stream(&config, write_i16, err_fn),
-            SampleFormat::U16 => device.build_output_stream(&config, write_u16, err_fn),
-        } {
-            Ok(s) => s,
-            Err(BuildStreamError::DeviceNotAvailable) => return Err(Error::DeviceNotAvailable),
-            Err(BuildStreamError::StreamConfigNotSupported) => return Err(Error::DeviceNotUsable),
-            Err(BuildStreamError::InvalidArgument) => return Err(Error::InvalidArgument),
-            Err(BuildStreamError::StreamIdOverflow) => return Err(Error::StreamIdOverflow),
-            Err(BuildStreamError::BackendSpecific { err }) => return Err(Error::CPALError(err)),
+    // Works out the latency, and spots underruns: if this buffer is due to be played noticeably later than the end of
+    // the last one, there was a gap
+    fn check_timing(&mut self, buffer: &[Sample], info: CallbackInfo) {
+        let frames = buffer.len() / self.channels;
+        self.latency = match (info.callback, info.playback) {
+            (Some(callback), Some(playback)) => playback.saturating_sub(callback),
+            // Without timestamps, assume this buffer is played as soon as the one already playing has finished
+            _ => Duration::from_secs_f64(frames as f64 / f64::from(self.sample_rate)),
         };
+        let playback = match info.playback {
+            Some(playback) => playback,
+            None => return,
+        };
+        if let Some((last_playback, last_frames)) = self.last_buffer {
+            let expected = Duration::from_secs_f64(last_frames as f64 / f64::from(self.sample_rate));
+            if let Some(elapsed) = playback.checked_sub(last_playback) {
+                if elapsed > expected + expected / 2 {
+                    let _ = self.shared.events.push(StreamEvent::Underrun { gap: elapsed - expected });
+                }
+            }
+        }
+        self.last_buffer = Some((playback, frames));
+    }
+
+    // Fills the buffer, and returns how many of its samples came from the Source. If that's fewer than the whole
+    // buffer, the Source has ended (or it's paused, or has panicked) and the rest is silent.
+    pub(crate) fn render(&mut self, buffer: &mut [Sample]) -> usize {
+        buffer.iter_mut().for_each(|s| *s = 0.0);
+        let start = self.source_frame();
+
+        // Stay silent while paused, or if the Source has panicked and hasn't been replaced yet
+        let target = if self.shared.paused.load(Ordering::Relaxed) { 0.0 } else { 1.0 };
+        if (self.gain == 0.0 && target == 0.0) || self.shared.panicked.load(Ordering::Relaxed) {
+            self.shared.silent.store(true, Ordering::Relaxed);
+            self.shared.clock.publish(self.latency, start, start, false);
+            return 0
+        }
+        self.shared.silent.store(false, Ordering::Relaxed);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.pull(buffer)));
+        let written = match result {
+            Ok(written) => written,
+            Err(payload) => {
+                self.shared.source_panicked(payload);
+                buffer.iter_mut().for_each(|s| *s = 0.0);
+                self.shared.clock.publish(self.latency, start, start, false);
+                return 0
+            },
+        };
+        self.rendered += (buffer.len() / self.channels) as u64;
+        self.shared.clock.publish(self.latency, start, self.source_frame(), true);
+
+        if self.gain != target {
+            for frame in buffer.chunks_exact_mut(self.channels) {
+                self.gain = if target > self.gain {
+                    (self.gain + self.fade_step).min(target)
+                } else {
+                    (self.gain - self.fade_step).max(target)
+                };
+                frame.iter_mut().for_each(|s| *s *= self.gain);
+            }
+        }
+        written
+    }
+
+    // Returns the clock's position, in the Source's frames, at the end of the last buffer
+    fn source_frame(&self) -> u64 {
+        self.base_frame
+            + (u128::from(self.rendered) * u128::from(self.source_rate) / u128::from(self.sample_rate)) as u64
+    }
+
+    // Returns how many samples of the buffer came from the Source
+    fn pull(&mut self, buffer: &mut [Sample]) -> usize {
+        match &self.matrix {
+            None => self.source.write_samples(buffer),
+            Some(matrix) => {
+                let frames = buffer.len() / matrix.output_count();
+                self.scratch.clear();
+                self.scratch.resize(frames * matrix.input_count(), 0.0);
+                let written = self.source.write_samples(&mut self.scratch);
+                matrix.mix_into(&self.scratch, buffer);
+                written / matrix.input_count() * matrix.output_count()
+            },
+        }
+    }
+
+    // Returns the Source
+    pub(crate) fn source_mut(&mut self) -> &mut S {
+        match &mut self.source {
+            Root::Direct(source) => source,
+            Root::Resampled(resampler) => resampler.source_mut(),
+        }
+    }
+
+    // Consumes the Renderer, and returns its Source
+    pub(crate) fn into_source(self) -> S {
+        match self.source {
+            Root::Direct(source) => source,
+            Root::Resampled(resampler) => resampler.into_inner(),
+        }
+    }
+}
+
+impl<S> Root<S>
+where
+    S: Source,
+{
+    fn write_samples(&mut self, buffer: &mut [Sample]) -> usize {
+        match self {
+            Self::Direct(source) => source.write_samples(buffer),
+            Self::Resampled(resampler) => resampler.write_samples(buffer),
+        }
+    }
+}
+
+impl<S> SourceLink<S> {
+    fn new(source: S) -> Self {
+        Self {
+            slot: AtomicPtr::new(Box::into_raw(Box::new(source))),
+            commands: Queue::with_capacity(COMMAND_QUEUE_SIZE),
+            garbage: Queue::with_capacity(COMMAND_QUEUE_SIZE * 2),
+            _source: PhantomData,
+        }
+    }
+
+    // Takes the Source from the slot, if it's there
+    fn take(&self) -> Option<Box<S>> {
+        let source = self.slot.swap(ptr::null_mut(), Ordering::Acquire);
+        // SAFETY: the slot only ever holds null or a pointer from Box::into_raw, and swapping it out means nobody else
+        // can take the same pointer
+        (!source.is_null()).then(|| unsafe { Box::from_raw(source) })
+    }
+
+    // Leaves the Source in the slot. Only whoever took it out can put it back, so the slot is always empty here.
+    fn put(&self, source: Box<S>) {
+        let old = self.slot.swap(Box::into_raw(source), Ordering::Release);
+        debug_assert!(old.is_null());
+    }
 
-        match stream.play() {
-            Err(PlayStreamError::DeviceNotAvailable) => return Err(Error::DeviceNotAvailable),
-            Err(PlayStreamError::BackendSpecific { err }) => return Err(Error::CPALError(err)),
-            _ => (),
+    // Runs every queued command on the Source, then sends it back to be dropped. The garbage queue only fills up if
+    // nobody has called `with_source()` in a long time, and then there's nowhere else for it to go.
+    fn run_commands(&self, source: &mut S) {
+        while let Some(mut command) = self.commands.pop() {
+            command.call(source);
+            let _ = self.garbage.push(command);
         }
+    }
+
+    // Drops any commands which have been run. Never called on the audio thread.
+    fn collect_garbage(&self) {
+        while self.garbage.pop().is_some() {}
+    }
+}
+
+impl<S, F, R> Call<S> for Request<F, R>
+where
+    F: FnOnce(&mut S) -> R + Send,
+    R: Send,
+{
+    // A call which was cancelled is left alone, so that the closure is dropped along with the box, off the audio thread
+    fn call(&mut self, source: &mut S) {
+        let state = &self.reply.state;
+        if state.compare_exchange(CALL_PENDING, CALL_RUNNING, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return
+        }
+        if let Some(f) = self.f.take() {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| f(source)));
+            // SAFETY: moving the state on from CALL_PENDING gave this thread the result until it's marked done
+            unsafe { *self.reply.result.get() = Some(result) };
+            state.store(CALL_DONE, Ordering::Release);
+        }
+    }
+}
+
+impl<R> Reply<R> {
+    // Stops the call from being run, unless it already has been, or is being run right now. Returns true if it was
+    // stopped.
+    fn cancel(&self) -> bool {
+        self.state.compare_exchange(CALL_PENDING, CALL_CANCELLED, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+    }
+}
 
-        Ok(OutputStream { _stream: stream, _source: source, sample_rate, channel_count })
+// SAFETY: `result` is only ever used by one thread at a time, as laid out on the struct
+unsafe impl<R: Send> Sync for Reply<R> {}
+
+// SAFETY: the Source is only ever used by one thread at a time, whoever took it out of the slot, so the link can be
+// shared as long as the Source can be sent between threads
+unsafe impl<S: Send> Sync for SourceLink<S> {}
+
+impl<S> Drop for SourceLink<S> {
+    fn drop(&mut self) {
+        self.take();
+    }
+}
+
+impl<S> StreamRenderer<S>
+where
+    S: Source + Send + 'static,
+{
+    fn render(&mut self, buffer: &mut [Sample], info: CallbackInfo) {
+        if let Some(renderer) = self.renderer.as_mut() {
+            self.link.run_commands(renderer.source_mut());
+            renderer.check_timing(buffer, info);
+            renderer.render(buffer);
+        }
+    }
+}
+
+impl<S> Drop for StreamRenderer<S>
+where
+    S: Source + Send + 'static,
+{
+    fn drop(&mut self) {
+        if let Some(renderer) = self.renderer.take() {
+            self.link.put(Box::new(renderer.into_source()));
+        }
     }
 }
@@ -1,5 +1,13 @@
-use super::Source;
-use std::sync::Arc;
+use super::{
+    Sample, Source,
+    convert::{self, Converter, Dither, FromSample},
+};
+use std::{
+    convert::TryFrom,
+    fmt,
+    io::{self, Write},
+    sync::Arc,
+};
 
 /// A Source object for decoding and playing samples from a .wav file.
 ///
@@ -31,6 +39,21 @@ pub enum Error {
     UnknownFormat,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidFile => "not a .wav file",
+            Self::MalformedData => "the audio data is malformed",
+            Self::UnknownFormat => "the audio data is in an unsupported format",
+        })
+    }
+}
+
+impl std::error::Error for Error {}
+
+// How many frames are converted at a time when writing
+const CHUNK_FRAMES: usize = 4096;
+
 #[derive(Clone, Copy, Debug)]
 pub enum Format {
     U8,
@@ -112,6 +135,87 @@ impl WavPlayer {
     }
 }
 
+/// Writes interleaved samples to `writer` as a .wav file in the given format. Samples are converted the same way
+/// OutputStream converts them for a device, so they're clipped at -1.0 and 1.0 in any format except F32.
+/// 8 and 16-bit samples get the given dither; 24 and 32-bit samples don't need any, so they're just rounded.
+/// See the `convert` module.
+///
+/// The writer isn't buffered here, so wrap a File in a BufWriter before passing it in. If there are no channels, or
+/// the length, channel count or sample rate is too big for a .wav header, nothing is written and an `InvalidInput`
+/// error is returned.
+pub fn write(
+    mut writer: impl Write,
+    samples: &[Sample],
+    channels: u16,
+    sample_rate: u32,
+    format: Format,
+    dither: Dither,
+) -> io::Result<()> {
+    let (audio_format, sample_bytes): (u16, u16) = match format {
+        Format::U8 => (1, 1),
+        Format::I16 => (1, 2),
+        Format::I24 => (1, 3),
+        Format::I32 => (1, 4),
+        Format::F32 => (3, 4),
+    };
+    let data_len = samples.len() * usize::from(sample_bytes);
+    let data_len = u32::try_from(data_len)
+        .ok()
+        .filter(|len| *len <= u32::MAX - 36)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many samples for a .wav file"))?;
+    if channels == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a .wav file needs at least one channel"))
+    }
+    let block_align = channels
+        .checked_mul(sample_bytes)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many channels for a .wav file"))?;
+    let byte_rate = sample_rate
+        .checked_mul(u32::from(block_align))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "sample rate too high for a .wav file"))?;
+
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(36 + data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&audio_format.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&(sample_bytes * 8).to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    writer.write_all(&header)?;
+
+    // Convert a chunk at a time rather than making a second copy of the whole thing. Chunks are made of whole frames,
+    // so the converter can tell which channel each sample belongs to.
+    let mut converter = Converter::new(channels.into(), dither);
+    let chunk_len = CHUNK_FRAMES * usize::from(channels);
+    let mut bytes = Vec::with_capacity(chunk_len * usize::from(sample_bytes));
+    let mut shorts: Vec<i16> = Vec::new();
+    for chunk in samples.chunks(chunk_len) {
+        bytes.clear();
+        match format {
+            Format::U8 => {
+                bytes.resize(chunk.len(), 0);
+                converter.convert(chunk, &mut bytes);
+            },
+            Format::I16 => {
+                shorts.clear();
+                shorts.resize(chunk.len(), 0);
+                converter.convert(chunk, &mut shorts);
+                shorts.iter().for_each(|s| bytes.extend_from_slice(&s.to_le_bytes()));
+            },
+            Format::I24 => chunk.iter().for_each(|s| bytes.extend_from_slice(&convert::to_i24(*s).to_le_bytes()[..3])),
+            Format::I32 => chunk.iter().for_each(|s| bytes.extend_from_slice(&i32::from_sample(*s).to_le_bytes())),
+            Format::F32 => chunk.iter().for_each(|s| bytes.extend_from_slice(&s.to_le_bytes())),
+        }
+        writer.write_all(&bytes)?;
+    }
+    writer.flush()
+}
+
 impl Source for WavPlayer {
     fn write_samples(&mut self, buffer: &mut [f32]) -> usize {
         use std::convert::TryInto;